@@ -0,0 +1,213 @@
+//! A single simulated player, driven through the client's game logic.
+
+use std::{
+    collections::HashMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use netcode::{
+    client::{Connection, Game, GameEvent},
+    event::PlayerPreferences,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use uuid::Uuid;
+
+/// Time between two frames of a bot, as with a 60 FPS client
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Time to wait for an answer to a join request before sending it again
+const JOIN_RETRY: Duration = Duration::from_secs(1);
+
+/// Time a scripted bot walks in one direction before turning around and jumping
+const SCRIPT_LEG: Duration = Duration::from_secs(1);
+
+/// Time a random bot keeps its direction before picking a new one
+const RANDOM_STEP: Duration = Duration::from_millis(500);
+
+/// Chance that a random bot jumps when it picks a new direction
+const RANDOM_JUMP_CHANCE: f64 = 0.2;
+
+/// How the bots move and jump
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Pattern {
+    /// Walk right then left, jumping each time the bot turns around
+    Script,
+    /// Walk in a random direction, or stand still, and jump at random
+    Random,
+}
+
+/// What a bot measured while it ran
+#[derive(Debug, Default)]
+pub struct BotReport {
+    pub connected: bool,
+    pub joined: bool,
+    pub join_rejections: usize,
+    /// Time between sending each movement and the server acknowledging it
+    pub ack_latencies: Vec<Duration>,
+    /// Magnitude of each reconciliation correction
+    pub corrections: Vec<f64>,
+    /// Number of times the bot became it, in the tag game mode
+    pub tagged: usize,
+    /// Errors reported by the server, by message
+    pub server_errors: HashMap<String, usize>,
+    pub client_errors: usize,
+    pub disconnected: bool,
+}
+
+/// Settings shared by every bot
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub address: String,
+    pub ping_ms: u64,
+    pub pattern: Pattern,
+    pub duration: Duration,
+    pub seed: u64,
+}
+
+/// Connects a bot to the server, joins the game as `Bot <index>` and plays the pattern until
+/// the duration has passed
+pub fn run(index: usize, config: &BotConfig) -> BotReport {
+    let mut report = BotReport::default();
+    let Ok(mut game) = Game::connect(&config.address) else {
+        return report;
+    };
+    report.connected = true;
+    game.set_simulated_ping(config.ping_ms);
+
+    let mut bot = Bot {
+        name: format!("Bot {index}"),
+        pattern: config.pattern,
+        rng: StdRng::seed_from_u64(config.seed.wrapping_add(index as u64)),
+        direction: 1.,
+        turned_at: Instant::now(),
+        last_frame: Instant::now(),
+        join_sent_at: None,
+        sent: HashMap::new(),
+    };
+    let end = Instant::now() + config.duration;
+    while Instant::now() < end && !report.disconnected {
+        bot.frame(&mut game, &mut report);
+        thread::sleep(FRAME_INTERVAL);
+    }
+    report.joined = game.player_idx.is_some();
+    report
+}
+
+/// State of a bot between frames
+struct Bot {
+    name: String,
+    pattern: Pattern,
+    rng: StdRng,
+    /// -1 to walk left, 1 to walk right, 0 to stand still
+    direction: f32,
+    turned_at: Instant,
+    last_frame: Instant,
+    /// When the last join request was sent, while waiting for an answer
+    join_sent_at: Option<Instant>,
+    /// Movements sent and not acknowledged yet, with the time they were sent at
+    sent: HashMap<Uuid, Instant>,
+}
+
+impl Bot {
+    /// Updates the game, then joins or plays the pattern for one frame
+    fn frame(&mut self, game: &mut Game, report: &mut BotReport) {
+        let frame_time = self.last_frame.elapsed();
+        self.last_frame = Instant::now();
+        game.update();
+        self.track_acks(game, report);
+        self.handle_events(game, report);
+
+        if game.player_idx.is_none() {
+            self.join(game);
+            return;
+        }
+        self.play(game, frame_time);
+    }
+
+    /// Records the latency of the movements acknowledged since the last frame, and starts
+    /// timing the ones sent
+    fn track_acks(&mut self, game: &Game, report: &mut BotReport) {
+        let now = Instant::now();
+        self.sent.retain(|id, sent_at| {
+            let acknowledged = !game.unacknowledged.contains_key(id);
+            if acknowledged {
+                report.ack_latencies.push(now - *sent_at);
+            }
+            !acknowledged
+        });
+        for id in game.unacknowledged.keys() {
+            self.sent.entry(*id).or_insert(now);
+        }
+    }
+
+    fn handle_events(&mut self, game: &mut Game, report: &mut BotReport) {
+        let player_idx = game.player_idx;
+        for event in game.poll_events() {
+            match event {
+                GameEvent::Joined { .. } => self.join_sent_at = None,
+                GameEvent::Tagged { player_id } if Some(player_id) == player_idx => {
+                    report.tagged += 1;
+                }
+                // Rejected bots ask again once the retry delay has passed, queued bots wait
+                // for their turn
+                GameEvent::JoinRejected(_) => report.join_rejections += 1,
+                GameEvent::CorrectionApplied { delta_x } => report.corrections.push(delta_x.abs()),
+                GameEvent::ServerError(message) => {
+                    *report.server_errors.entry(message).or_default() += 1;
+                }
+                GameEvent::Error(_) => report.client_errors += 1,
+                GameEvent::Disconnected => report.disconnected = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// Asks to join the game once the server accepted the connection, again if the server did
+    /// not answer in time
+    fn join(&mut self, game: &Game) {
+        if game.connection != Connection::Connected
+            || game.queue_position.is_some()
+            || self
+                .join_sent_at
+                .is_some_and(|sent_at| sent_at.elapsed() < JOIN_RETRY)
+        {
+            return;
+        }
+        if game.join(&self.name, PlayerPreferences::default()).is_ok() {
+            self.join_sent_at = Some(Instant::now());
+        }
+    }
+
+    /// Moves and jumps the bot's player following its pattern
+    fn play(&mut self, game: &mut Game, frame_time: Duration) {
+        let mut jump = false;
+        match self.pattern {
+            Pattern::Script if self.turned_at.elapsed() >= SCRIPT_LEG => {
+                self.direction = -self.direction;
+                self.turned_at = Instant::now();
+                jump = true;
+            }
+            Pattern::Random if self.turned_at.elapsed() >= RANDOM_STEP => {
+                self.direction = self.rng.random_range(-1..=1) as f32;
+                self.turned_at = Instant::now();
+                jump = self.rng.random_bool(RANDOM_JUMP_CHANCE);
+            }
+            _ => {}
+        }
+
+        if self.direction != 0. {
+            let delta_x =
+                self.direction * game.config.max_units_per_second as f32 * frame_time.as_secs_f32();
+            let _ = game.move_player(delta_x);
+        }
+        let on_ground = game
+            .player_idx
+            .and_then(|id| game.local_state.players.get(&id))
+            .is_some_and(|player| player.y() <= 0.);
+        if jump && on_ground {
+            let _ = game.jump();
+        }
+    }
+}
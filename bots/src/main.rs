@@ -0,0 +1,85 @@
+//! Headless load-testing client, playing many simulated players against a server at once.
+
+use std::{thread, time::Duration};
+
+use bot::{BotConfig, Pattern};
+use clap::Parser;
+use report::Summary;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+mod bot;
+mod report;
+
+/// Command line flags of the load tester. Every flag can also be set through its environment
+/// variable.
+#[derive(Debug, Parser)]
+#[command(about = "NetCube load-testing bots")]
+struct Cli {
+    /// Address of the server to connect to
+    #[arg(long, env = "NETCUBE_SERVER", default_value = "http://localhost:7878")]
+    server: String,
+
+    /// Number of bots to run at once
+    #[arg(short, long, env = "NETCUBE_BOTS", default_value_t = 10)]
+    bots: usize,
+
+    /// Seconds the bots play for before the report is printed
+    #[arg(short, long, env = "NETCUBE_BOTS_DURATION", default_value_t = 30)]
+    duration: u64,
+
+    /// Simulated ping of every bot, in milliseconds
+    #[arg(long, env = "NETCUBE_BOTS_PING_MS", default_value_t = 0)]
+    ping_ms: u64,
+
+    /// How the bots move and jump
+    #[arg(long, env = "NETCUBE_BOTS_PATTERN", value_enum, default_value_t = Pattern::Random)]
+    pattern: Pattern,
+
+    /// Seed of the random pattern, so runs can be repeated
+    #[arg(long, env = "NETCUBE_BOTS_SEED", default_value_t = 0)]
+    seed: u64,
+
+    /// Milliseconds between two bots connecting, to spread the connections out
+    #[arg(long, env = "NETCUBE_BOTS_RAMP_UP_MS", default_value_t = 20)]
+    ramp_up_ms: u64,
+}
+
+fn main() -> anyhow::Result<()> {
+    // Log filter directives are read from RUST_LOG, such as `info,netcode=debug`
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn,bots=info")),
+        )
+        .init();
+
+    let cli = Cli::parse();
+    let config = BotConfig {
+        address: cli.server,
+        ping_ms: cli.ping_ms,
+        pattern: cli.pattern,
+        duration: Duration::from_secs(cli.duration),
+        seed: cli.seed,
+    };
+    info!(
+        bots = cli.bots,
+        server = config.address,
+        duration_s = cli.duration,
+        "starting bots"
+    );
+
+    let handles = (0..cli.bots)
+        .map(|index| {
+            let config = config.clone();
+            let handle = thread::Builder::new()
+                .name(format!("bot-{index}"))
+                .spawn(move || bot::run(index, &config));
+            thread::sleep(Duration::from_millis(cli.ramp_up_ms));
+            handle
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let reports = handles.into_iter().filter_map(|handle| handle.join().ok());
+
+    println!("{}", Summary::new(reports));
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Summary of what every bot measured during a load test.
+
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use crate::bot::BotReport;
+
+/// Measurements of every bot, merged together
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub bots: usize,
+    pub connected: usize,
+    pub joined: usize,
+    pub join_rejections: usize,
+    pub disconnected: usize,
+    ack_latencies: Vec<Duration>,
+    corrections: Vec<f64>,
+    tagged: usize,
+    server_errors: BTreeMap<String, usize>,
+    client_errors: usize,
+}
+
+impl Summary {
+    /// Merges the reports of every bot
+    pub fn new(reports: impl IntoIterator<Item = BotReport>) -> Self {
+        let mut summary = Summary::default();
+        for report in reports {
+            summary.bots += 1;
+            summary.connected += usize::from(report.connected);
+            summary.joined += usize::from(report.joined);
+            summary.join_rejections += report.join_rejections;
+            summary.disconnected += usize::from(report.disconnected);
+            summary.ack_latencies.extend(report.ack_latencies);
+            summary.corrections.extend(report.corrections);
+            summary.tagged += report.tagged;
+            for (message, count) in report.server_errors {
+                *summary.server_errors.entry(message).or_default() += count;
+            }
+            summary.client_errors += report.client_errors;
+        }
+        summary.ack_latencies.sort_unstable();
+        summary.corrections.sort_unstable_by(f64::total_cmp);
+        summary
+    }
+}
+
+/// Value at the given percentile of sorted values
+fn percentile<T: Copy>(sorted: &[T], percentile: f64) -> Option<T> {
+    let index = ((sorted.len() as f64 - 1.) * percentile / 100.).round() as usize;
+    sorted.get(index).copied()
+}
+
+/// Average of the values, or 0 without any value
+fn mean(values: impl ExactSizeIterator<Item = f64>) -> f64 {
+    let count = values.len();
+    if count == 0 {
+        return 0.;
+    }
+    values.sum::<f64>() / count as f64
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Bots: {} ({} connected, {} joined, {} disconnected early)",
+            self.bots, self.connected, self.joined, self.disconnected
+        )?;
+        writeln!(f, "Join rejections: {}", self.join_rejections)?;
+
+        let ms =
+            |latency: Option<Duration>| latency.map_or(0., |latency| latency.as_secs_f64() * 1000.);
+        writeln!(
+            f,
+            "Ack latency: {} acks, mean {:.1}ms, p50 {:.1}ms, p95 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            self.ack_latencies.len(),
+            mean(self.ack_latencies.iter().map(|latency| ms(Some(*latency)))),
+            ms(percentile(&self.ack_latencies, 50.)),
+            ms(percentile(&self.ack_latencies, 95.)),
+            ms(percentile(&self.ack_latencies, 99.)),
+            ms(self.ack_latencies.last().copied()),
+        )?;
+        writeln!(
+            f,
+            "Corrections: {}, mean {:.3} units, p95 {:.3} units, max {:.3} units",
+            self.corrections.len(),
+            mean(self.corrections.iter().copied()),
+            percentile(&self.corrections, 95.).unwrap_or(0.),
+            self.corrections.last().copied().unwrap_or(0.),
+        )?;
+
+        writeln!(f, "Times tagged: {}", self.tagged)?;
+
+        let server_errors = self.server_errors.values().sum::<usize>();
+        writeln!(f, "Server errors: {server_errors}")?;
+        for (message, count) in &self.server_errors {
+            writeln!(f, "  {count} x {message}")?;
+        }
+        write!(f, "Client errors: {}", self.client_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let values = (1..=100).collect::<Vec<_>>();
+        assert_eq!(percentile(&values, 0.), Some(1));
+        assert_eq!(percentile(&values, 50.), Some(51));
+        assert_eq!(percentile(&values, 95.), Some(95));
+        assert_eq!(percentile(&values, 100.), Some(100));
+        assert_eq!(percentile(&[7], 99.), Some(7));
+        assert_eq!(percentile::<u32>(&[], 50.), None);
+    }
+
+    #[test]
+    fn test_mean() {
+        assert_eq!(mean([1., 2., 6.].into_iter()), 3.);
+        assert_eq!(mean(std::iter::empty()), 0.);
+    }
+
+    #[test]
+    fn test_summary_display() {
+        let reports = [
+            BotReport {
+                connected: true,
+                joined: true,
+                ack_latencies: vec![Duration::from_millis(30), Duration::from_millis(10)],
+                corrections: vec![0.5],
+                tagged: 2,
+                server_errors: HashMap::from([("[ERROR - Player] no player".to_string(), 1)]),
+                ..Default::default()
+            },
+            BotReport {
+                connected: true,
+                join_rejections: 3,
+                ack_latencies: vec![Duration::from_millis(20)],
+                server_errors: HashMap::from([("[ERROR - Player] no player".to_string(), 2)]),
+                client_errors: 1,
+                disconnected: true,
+                ..Default::default()
+            },
+        ];
+        let summary = Summary::new(reports);
+        assert_eq!(
+            summary.to_string(),
+            "Bots: 2 (2 connected, 1 joined, 1 disconnected early)\n\
+             Join rejections: 3\n\
+             Ack latency: 3 acks, mean 20.0ms, p50 20.0ms, p95 30.0ms, p99 30.0ms, max 30.0ms\n\
+             Corrections: 1, mean 0.500 units, p95 0.500 units, max 0.500 units\n\
+             Times tagged: 2\n\
+             Server errors: 3\n  \
+             3 x [ERROR - Player] no player\n\
+             Client errors: 1"
+        );
+    }
+
+    #[test]
+    fn test_empty_summary_display() {
+        let summary = Summary::new([]).to_string();
+        assert!(summary.contains("Ack latency: 0 acks, mean 0.0ms, p50 0.0ms"));
+        assert!(summary.contains("Corrections: 0, mean 0.000 units"));
+    }
+}
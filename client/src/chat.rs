@@ -0,0 +1,100 @@
+//! Chat input box and scrolling chat log.
+
+use macroquad::{
+    input::{get_char_pressed, get_keys_pressed, KeyCode},
+    math::Vec2,
+    ui::{root_ui, widgets::Label, Skin},
+    window::screen_height,
+};
+use netcode::{client::Game, MAX_CHAT_LENGTH};
+use tracing::warn;
+
+use crate::GROUND_HEIGHT;
+
+/// Number of chat messages shown at once in the chat log
+const CHAT_LOG_LINES: usize = 5;
+
+/// Chat message being typed, and how far back the chat log is scrolled
+#[derive(Debug, Default)]
+pub struct Chat {
+    /// Whether the input box is open. The game's keybinds are disabled while typing
+    pub open: bool,
+    input: String,
+    /// Number of messages the log is scrolled back from the newest one
+    scroll: usize,
+}
+
+impl Chat {
+    /// Handles the keys pressed this frame: `Enter` opens the input box and sends the message,
+    /// `Escape` closes it, and `PageUp`/`PageDown` scroll the chat log.
+    pub fn handle_keys(&mut self, game: &Game) {
+        for key in get_keys_pressed() {
+            match key {
+                KeyCode::Enter if self.open => {
+                    if !self.input.trim().is_empty() {
+                        if let Err(err) = game.send_chat(&self.input) {
+                            warn!("{err}");
+                        }
+                    }
+                    self.input.clear();
+                    self.open = false;
+                }
+                KeyCode::Enter => self.open = true,
+                KeyCode::Escape if self.open => {
+                    self.input.clear();
+                    self.open = false;
+                }
+                KeyCode::Backspace if self.open => {
+                    self.input.pop();
+                }
+                KeyCode::PageUp => {
+                    let max_scroll = game.chat.len().saturating_sub(CHAT_LOG_LINES);
+                    self.scroll = (self.scroll + 1).min(max_scroll);
+                }
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        // Typed characters are consumed even when closed, so they do not leak into the box
+        while let Some(c) = get_char_pressed() {
+            if self.open && !c.is_control() && self.input.chars().count() < MAX_CHAT_LENGTH {
+                self.input.push(c);
+            }
+        }
+    }
+
+    /// Draws the chat log over the ground, with the input box below it while typing
+    pub fn draw(&self, game: &Game, skin: &Skin) {
+        root_ui().pop_skin();
+        root_ui().push_skin(skin);
+
+        let end = game.chat.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(CHAT_LOG_LINES);
+        let top = screen_height() * GROUND_HEIGHT + 10.;
+
+        for (i, message) in game.chat.range(start..end).enumerate() {
+            let sent_at = message.sent_at.with_timezone(&chrono::Local);
+            Label::new(format!(
+                "[{}] {}: {}",
+                sent_at.format("%H:%M"),
+                message.name,
+                message.text
+            ))
+            .position(Vec2 {
+                x: 15.,
+                y: top + i as f32 * 28.,
+            })
+            .ui(&mut root_ui());
+        }
+
+        if self.open {
+            Label::new(format!("> {}_", self.input))
+                .position(Vec2 {
+                    x: 15.,
+                    y: top + CHAT_LOG_LINES as f32 * 28.,
+                })
+                .ui(&mut root_ui());
+        }
+    }
+}
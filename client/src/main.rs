@@ -1,18 +1,29 @@
 //! Main entrypoint for the client-side graphical application.
 
-use std::collections::HashSet;
+use std::{collections::HashSet, path::Path};
 
+use chat::Chat;
+use chrono::{DateTime, Utc};
 use macroquad::{
     audio::Sound,
-    color::{Color, BLUE, BROWN, GREEN, PURPLE, RED, YELLOW},
+    color::{Color, BLUE, BROWN, GREEN, ORANGE, PURPLE, RED, WHITE, YELLOW},
     input::{get_keys_down, get_keys_pressed, KeyCode},
-    shapes::draw_rectangle,
+    shapes::{draw_rectangle, draw_rectangle_lines},
+    text::{draw_text, measure_text},
     time::get_frame_time,
     ui::{root_ui, Skin},
     window::{next_frame, request_new_screen_size, screen_height, screen_width},
 };
-use netcode::{client::Game, MAX_UNITS_PER_SECOND};
-use ui::draw_ui;
+use netcode::{
+    client::{Game, GameEvent},
+    event::PlayerPreferences,
+    tag::GameMode,
+    State,
+};
+use replay::ReplayViewer;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+use ui::{draw_connection_closed, draw_net_stats, draw_scores, draw_ui};
 
 /// Player's dimentions in x and y axis measured in pixels
 const PLAYER_SIZE: f32 = 30.;
@@ -29,11 +40,46 @@ const PLAYER_COLORS: [Color; 5] = [RED, GREEN, BLUE, YELLOW, PURPLE];
 /// Number of pixels per server-side units. Used for rendering.
 const PIXELS_PER_UNIT: f32 = 40.;
 
+/// Font size of the name tags drawn above the players
+const NAME_TAG_SIZE: f32 = 20.;
+
+/// Thickness of the outline drawn around the player that is it, in pixels
+const IT_OUTLINE: f32 = 4.;
+
+/// Name and preferences sent when joining the game
+struct Profile {
+    name: String,
+    preferences: PlayerPreferences,
+}
+
+impl Profile {
+    /// Reads the player's name from NETCUBE_NAME and colour index from NETCUBE_COLOR.
+    /// Defaults to a name made from the process id.
+    fn from_env() -> Self {
+        let name = std::env::var("NETCUBE_NAME")
+            .unwrap_or_else(|_| format!("Cube {}", std::process::id() % 1000));
+        let color = std::env::var("NETCUBE_COLOR")
+            .ok()
+            .and_then(|color| color.parse().ok());
+        Self {
+            name,
+            preferences: PlayerPreferences { color },
+        }
+    }
+}
+
+mod chat;
+mod replay;
 mod ui;
 
 #[macroquad::main("BasicShapes")]
 async fn main() -> anyhow::Result<()> {
-    let mut game = Game::new();
+    // Log filter directives are read from RUST_LOG, such as `info,netcode=debug`
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
     let font = include_bytes!("../assets/font.ttf");
     let join_sound = macroquad::audio::load_sound_from_bytes(include_bytes!("../assets/join.wav"))
@@ -91,37 +137,118 @@ async fn main() -> anyhow::Result<()> {
 
     request_new_screen_size(1600., 900.);
 
+    // Plays back a replay file recorded by the server instead of connecting to it
+    if let Ok(path) = std::env::var("NETCUBE_REPLAY") {
+        let viewer = ReplayViewer::load(Path::new(&path))?;
+        play_replay(viewer, &label_skin).await;
+        return Ok(());
+    }
+
+    let mut game = match std::env::var("NETCUBE_SERVER") {
+        Ok(address) => Game::connect(&address)?,
+        Err(_) => Game::new()?,
+    };
+
+    let profile = Profile::from_env();
+
+    let mut show_net_stats = false;
+    let mut chat = Chat::default();
+
     loop {
         draw_ground();
 
-        draw_players(&mut game);
+        draw_players(&game.display_state, game.following, Utc::now());
 
-        handle_keys(&mut game, &join_sound, &ping_sound);
+        chat.handle_keys(&game);
+        if !chat.open {
+            handle_keys(&mut game, &profile, &mut show_net_stats, &ping_sound);
+        }
 
         draw_ui(&mut game, &label_skin, &active_skin, &inactive_skin);
 
+        if show_net_stats {
+            draw_net_stats(&game.net_stats(), &label_skin);
+        }
+
+        if game.config.mode == GameMode::Tag {
+            draw_scores(&game.display_state, &label_skin);
+        }
+
+        chat.draw(&game, &label_skin);
+
+        draw_connection_closed(&game.connection, &inactive_skin);
+
         game.update();
 
+        handle_events(&mut game, &join_sound);
+
+        next_frame().await;
+    }
+}
+
+/// Plays back a replay with the game's drawing code, until the window is closed
+async fn play_replay(mut viewer: ReplayViewer, label_skin: &Skin) {
+    loop {
+        draw_ground();
+
+        viewer.update(get_frame_time());
+        draw_players(&viewer.display_state, viewer.perspective, viewer.time);
+
+        viewer.handle_keys();
+        viewer.draw_ui(label_skin);
+        if viewer.mode() == GameMode::Tag {
+            draw_scores(&viewer.display_state, label_skin);
+        }
+
         next_frame().await;
     }
 }
 
-fn handle_key_press(key_codes: HashSet<KeyCode>, game: &mut Game, join_sound: &Sound) {
+/// Reacts to the events produced by the game since the last frame
+fn handle_events(game: &mut Game, join_sound: &Sound) {
+    let player_idx = game.player_idx;
+    for event in game.poll_events() {
+        match event {
+            GameEvent::Joined { .. } => macroquad::audio::play_sound_once(join_sound),
+            GameEvent::Tagged { player_id } if Some(player_id) == player_idx => {
+                info!("You are it, touch another player to pass the tag");
+            }
+            GameEvent::Spectating => info!("Spectating, press F to follow a player"),
+            GameEvent::JoinRejected(rejection) => match rejection.queue_position {
+                Some(position) => info!("{} Queued at position {position}", rejection.reason),
+                None => warn!("{}", rejection.reason),
+            },
+            GameEvent::ServerError(message) => error!("{message}"),
+            GameEvent::Error(err) => error!("{err}"),
+            GameEvent::DisconnectNotice(notice) => warn!("{notice}"),
+            GameEvent::Disconnected => warn!("Disconnected from the server"),
+            _ => {}
+        }
+    }
+}
+
+fn handle_key_press(
+    key_codes: HashSet<KeyCode>,
+    game: &mut Game,
+    profile: &Profile,
+    show_net_stats: &mut bool,
+) {
     for key in key_codes {
         match key {
             KeyCode::W => {
                 if let Some(idx) = game.player_idx {
                     if let Some(player) = game.local_state.players.get(&idx) {
                         if player.y() <= 0. {
-                            game.jump();
+                            if let Err(err) = game.jump() {
+                                warn!("{err}");
+                            }
                         }
                     }
                 }
             }
-            KeyCode::Space => {
-                if game.player_idx.is_none() {
-                    macroquad::audio::play_sound_once(join_sound);
-                    game.join();
+            KeyCode::Space if game.player_idx.is_none() => {
+                if let Err(err) = game.join(&profile.name, profile.preferences) {
+                    warn!("{err}");
                 }
             }
             KeyCode::P => {
@@ -130,33 +257,83 @@ fn handle_key_press(key_codes: HashSet<KeyCode>, game: &mut Game, join_sound: &S
                     game.reconciliation = false;
                 }
             }
+            KeyCode::I if game.spectating => {
+                game.spectator_settings.interpolation = !game.spectator_settings.interpolation;
+            }
             KeyCode::I => {
                 game.interpolation = !game.interpolation;
             }
+            KeyCode::V if game.player_idx.is_none() => {
+                if let Err(err) = game.spectate() {
+                    warn!("{err}");
+                }
+            }
+            KeyCode::F if game.spectating => game.follow_next(),
             KeyCode::R => {
                 game.reconciliation = !game.reconciliation;
                 if !game.prediction {
                     game.prediction = true
                 }
             }
-            _ => {}
+            KeyCode::N => {
+                *show_net_stats = !*show_net_stats;
+            }
+            KeyCode::C => {
+                if let Err(err) = game.create_room(None) {
+                    warn!("{err}");
+                }
+            }
+            KeyCode::L => {
+                if let Err(err) = game.list_rooms() {
+                    warn!("{err}");
+                }
+            }
+            _ => {
+                if let Some(room) = room_key_index(key).and_then(|i| game.rooms.get(i)) {
+                    if let Err(err) = game.join_room(room.id.clone()) {
+                        warn!("{err}");
+                    }
+                }
+            }
         }
     }
 }
 
-fn handle_keys(game: &mut Game, join_sound: &Sound, ping_sound: &Sound) {
+/// Index in the room list selected by the number keys 1-9
+fn room_key_index(key: KeyCode) -> Option<usize> {
+    const ROOM_KEYS: [KeyCode; 9] = [
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+    ROOM_KEYS.iter().position(|room_key| *room_key == key)
+}
+
+fn handle_keys(game: &mut Game, profile: &Profile, show_net_stats: &mut bool, ping_sound: &Sound) {
     let keys_down = get_keys_down();
     handle_key_hold(keys_down, game, ping_sound);
 
     let keys_pressed = get_keys_pressed();
-    handle_key_press(keys_pressed, game, join_sound);
+    handle_key_press(keys_pressed, game, profile, show_net_stats);
 }
 
 fn handle_key_hold(key_codes: HashSet<KeyCode>, game: &mut Game, ping_sound: &Sound) {
     for key in key_codes {
         match key {
-            KeyCode::D => game.move_player(MAX_UNITS_PER_SECOND as f32 * get_frame_time()),
-            KeyCode::A => game.move_player(-MAX_UNITS_PER_SECOND as f32 * get_frame_time()),
+            KeyCode::D | KeyCode::A if game.player_idx.is_some() => {
+                let direction = if key == KeyCode::D { 1. } else { -1. };
+                let delta_x =
+                    direction * game.config.max_units_per_second as f32 * get_frame_time();
+                if let Err(err) = game.move_player(delta_x) {
+                    warn!("{err}");
+                }
+            }
             KeyCode::J => {
                 let new_ping = game.ping_cache.saturating_sub(10);
                 game.set_simulated_ping(new_ping);
@@ -172,14 +349,50 @@ fn handle_key_hold(key_codes: HashSet<KeyCode>, game: &mut Game, ping_sound: &So
     }
 }
 
-fn draw_players(state: &mut Game) {
-    for player in state.display_state.players.values() {
+/// Draws the players as of the given time, keeping the followed player at the center of the
+/// screen if there is one
+fn draw_players(state: &State, following: Option<usize>, now: DateTime<Utc>) {
+    let camera_x = following
+        .and_then(|id| state.players.get(&id))
+        .map_or(0., |followed| {
+            followed.x as f32 * PIXELS_PER_UNIT + PLAYER_SIZE / 2. - screen_width() / 2.
+        });
+
+    for player in state.players.values() {
+        let x = player.x as f32 * PIXELS_PER_UNIT - camera_x;
+        let y = (screen_height() * GROUND_HEIGHT)
+            - PLAYER_SIZE
+            - (player.y_at(now) as f32 * JUMP_MULTIPLIER);
+        let color = player.preferences.color.unwrap_or(player.id);
         draw_rectangle(
-            player.x as f32 * PIXELS_PER_UNIT,
-            (screen_height() * GROUND_HEIGHT) - PLAYER_SIZE - (player.y() as f32 * JUMP_MULTIPLIER),
+            x,
+            y,
             PLAYER_SIZE,
             PLAYER_SIZE,
-            PLAYER_COLORS[player.id % PLAYER_COLORS.len()],
+            PLAYER_COLORS[color % PLAYER_COLORS.len()],
+        );
+
+        // The player that is it is outlined, with its name tag in the same color
+        let it = state.it == Some(player.id);
+        if it {
+            draw_rectangle_lines(
+                x - IT_OUTLINE,
+                y - IT_OUTLINE,
+                PLAYER_SIZE + 2. * IT_OUTLINE,
+                PLAYER_SIZE + 2. * IT_OUTLINE,
+                IT_OUTLINE,
+                ORANGE,
+            );
+        }
+
+        // Name tag centered above the player
+        let tag = measure_text(&player.name, None, NAME_TAG_SIZE as u16, 1.);
+        draw_text(
+            &player.name,
+            x + (PLAYER_SIZE - tag.width) / 2.,
+            y - 8. - if it { IT_OUTLINE } else { 0. },
+            NAME_TAG_SIZE,
+            if it { ORANGE } else { WHITE },
         );
     }
 }
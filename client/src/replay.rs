@@ -0,0 +1,245 @@
+//! Playback of a recorded game, to inspect it frame by frame without live clients.
+
+use std::path::Path;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use macroquad::{
+    input::{get_keys_down, get_keys_pressed, KeyCode},
+    math::Vec2,
+    ui::{root_ui, widgets::Label, Skin},
+};
+use netcode::{
+    replay::{Replay, ReplayError, ReplayEvent},
+    tag::GameMode,
+    State,
+};
+
+/// Number of entries between two saved states, so seeking does not re-run the whole replay
+const CHECKPOINT_ENTRIES: usize = 500;
+
+/// Time skipped when seeking backward or forward
+const SEEK_STEP: TimeDelta = TimeDelta::seconds(5);
+
+/// Playback speeds to choose from
+const SPEEDS: [f64; 7] = [0.1, 0.25, 0.5, 1., 2., 4., 8.];
+
+/// Index of the normal speed in [SPEEDS]
+const NORMAL_SPEED: usize = 3;
+
+/// A replay being played back, as seen by the server or by one of its players
+#[derive(Debug)]
+pub struct ReplayViewer {
+    replay: Replay,
+    /// States after applying the first entries of the replay, every [CHECKPOINT_ENTRIES]
+    checkpoints: Vec<State>,
+    end: DateTime<Utc>,
+    /// Time of the recorded game being shown
+    pub time: DateTime<Utc>,
+    pub paused: bool,
+    speed: usize,
+    /// Player whose view of the game is simulated. The server's view is shown if not set
+    pub perspective: Option<usize>,
+    /// One way latency of the simulated player's connection. The player sees itself in the
+    /// present, as with prediction, and the other players this late
+    pub latency_ms: u64,
+    /// State shown this frame
+    pub display_state: State,
+}
+
+impl ReplayViewer {
+    /// Loads a replay file, re-running it once to check it and to save its checkpoints
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        let replay = Replay::load(path)?;
+        let mut state = replay.initial_state();
+        let mut checkpoints = vec![state.clone()];
+        for (index, entry) in replay.entries.iter().enumerate() {
+            state.replay(index, entry)?;
+            if (index + 1) % CHECKPOINT_ENTRIES == 0 {
+                checkpoints.push(state.clone());
+            }
+        }
+
+        let start = replay.header.started_at;
+        let end = replay.entries.last().map_or(start, |entry| entry.at);
+        Ok(Self {
+            display_state: checkpoints[0].clone(),
+            replay,
+            checkpoints,
+            end,
+            time: start,
+            paused: true,
+            speed: NORMAL_SPEED,
+            perspective: None,
+            latency_ms: 0,
+        })
+    }
+
+    /// Playback speed multiplier
+    pub fn speed(&self) -> f64 {
+        SPEEDS[self.speed]
+    }
+
+    /// Time elapsed since the start of the replay
+    pub fn elapsed(&self) -> TimeDelta {
+        self.time - self.replay.header.started_at
+    }
+
+    /// Length of the replay
+    pub fn duration(&self) -> TimeDelta {
+        self.end - self.replay.header.started_at
+    }
+
+    /// Objective of the recorded game
+    pub fn mode(&self) -> GameMode {
+        self.replay.header.config.mode
+    }
+
+    /// Id of the recorded room
+    pub fn room_id(&self) -> &str {
+        &self.replay.header.room_id
+    }
+
+    /// Game state after every entry recorded up to the given time
+    fn state_at(&self, at: DateTime<Utc>) -> State {
+        let applied = self.replay.entries.partition_point(|entry| entry.at <= at);
+        let checkpoint = applied / CHECKPOINT_ENTRIES;
+        let mut state = self.checkpoints[checkpoint].clone();
+        let start = checkpoint * CHECKPOINT_ENTRIES;
+        for (index, entry) in self.replay.entries[start..applied].iter().enumerate() {
+            // Every entry was already applied without errors when loading
+            let _ = state.replay(start + index, entry);
+        }
+        state
+    }
+
+    /// Moves the playback to the given time, within the replay
+    pub fn seek(&mut self, to: DateTime<Utc>) {
+        self.time = to.clamp(self.replay.header.started_at, self.end);
+    }
+
+    /// Moves the playback to the next or previous tick boundary
+    pub fn step(&mut self, forward: bool) {
+        let mut ticks = self
+            .replay
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry.event, ReplayEvent::Tick))
+            .map(|entry| entry.at);
+        let tick = if forward {
+            ticks.find(|at| *at > self.time)
+        } else {
+            ticks.take_while(|at| *at < self.time).last()
+        };
+        if let Some(tick) = tick {
+            self.seek(tick);
+        }
+    }
+
+    /// Shows the next player's perspective, then the server's after the last player
+    pub fn next_perspective(&mut self) {
+        let mut ids = self
+            .display_state
+            .players
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        self.perspective = match self.perspective {
+            None => ids.first().copied(),
+            Some(current) => ids.into_iter().find(|id| *id > current),
+        };
+    }
+
+    /// Advances the playback by the frame's duration, then builds the state shown.
+    /// Playback pauses at the end of the replay.
+    pub fn update(&mut self, frame_time: f32) {
+        if !self.paused {
+            let elapsed = TimeDelta::microseconds((frame_time as f64 * self.speed() * 1e6) as i64);
+            self.seek(self.time + elapsed);
+            if self.time == self.end {
+                self.paused = true;
+            }
+        }
+
+        let mut state = self.state_at(self.time);
+        if let Some(player_id) = self.perspective {
+            let latency = TimeDelta::milliseconds(self.latency_ms as i64);
+            let own = state.players.remove(&player_id);
+            state = self.state_at(self.time - latency);
+            // Other players' jumps are seen as late as their positions
+            for player in state.players.values_mut() {
+                player.last_jump_at = player.last_jump_at.map(|at| at + latency);
+            }
+            state.players.remove(&player_id);
+            if let Some(own) = own {
+                state.players.insert(player_id, own);
+            }
+        }
+        self.display_state = state;
+    }
+
+    /// Handles the playback keys: `Space` plays and pauses, `Left`/`Right` seek, `,`/`.` step
+    /// through ticks, `Home` restarts, `Up`/`Down` change the speed, `Tab` switches the
+    /// perspective and `J`/`K` change the simulated latency.
+    pub fn handle_keys(&mut self) {
+        for key in get_keys_pressed() {
+            match key {
+                KeyCode::Space => self.paused = !self.paused,
+                KeyCode::Left => self.seek(self.time - SEEK_STEP),
+                KeyCode::Right => self.seek(self.time + SEEK_STEP),
+                KeyCode::Comma => self.step(false),
+                KeyCode::Period => self.step(true),
+                KeyCode::Home => self.seek(self.replay.header.started_at),
+                KeyCode::Up => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+                KeyCode::Down => self.speed = self.speed.saturating_sub(1),
+                KeyCode::Tab => self.next_perspective(),
+                _ => {}
+            }
+        }
+
+        for key in get_keys_down() {
+            match key {
+                KeyCode::J => self.latency_ms = self.latency_ms.saturating_sub(10),
+                KeyCode::K => self.latency_ms += 10,
+                _ => {}
+            }
+        }
+    }
+
+    /// Draws the playback's position, speed and perspective
+    pub fn draw_ui(&self, skin: &Skin) {
+        root_ui().pop_skin();
+        root_ui().push_skin(skin);
+
+        let status = if self.paused { "Paused" } else { "Playing" };
+        let perspective = match self.perspective {
+            Some(id) => {
+                let name = self.display_state.players.get(&id).map_or_else(
+                    || format!("player {id} (not in the game)"),
+                    |player| player.name.clone(),
+                );
+                format!("{name}, latency {}ms", self.latency_ms)
+            }
+            None => "Server".to_string(),
+        };
+        let lines = [
+            format!(
+                "Replay of {}: {} {:.2}s / {:.2}s at {}x",
+                self.room_id(),
+                status,
+                self.elapsed().as_seconds_f64(),
+                self.duration().as_seconds_f64(),
+                self.speed()
+            ),
+            format!("Perspective: {perspective}"),
+        ];
+        for (i, line) in lines.into_iter().enumerate() {
+            Label::new(line)
+                .position(Vec2 {
+                    x: 15.,
+                    y: 15. + i as f32 * 28.,
+                })
+                .ui(&mut root_ui());
+        }
+    }
+}
@@ -3,8 +3,12 @@
 use macroquad::{
     math::Vec2,
     ui::{root_ui, widgets::Label, Skin},
+    window::{screen_height, screen_width},
+};
+use netcode::{
+    client::{Connection, Game, NetStats},
+    State,
 };
-use netcode::client::Game;
 
 /// Draw the GUI to the canvas
 pub fn draw_ui(game: &mut Game, label_skin: &Skin, active_skin: &Skin, inactive_skin: &Skin) {
@@ -26,6 +30,45 @@ pub fn draw_ui(game: &mut Game, label_skin: &Skin, active_skin: &Skin, inactive_
         .position(Vec2 { x: 800., y: 15. })
         .ui(&mut root_ui());
 
+    Label::new(format!("Room: {}", game.room_id))
+        .position(Vec2 { x: 1050., y: 15. })
+        .ui(&mut root_ui());
+
+    if let Some(queue_position) = game.queue_position {
+        Label::new(format!("Queued #{queue_position}"))
+            .position(Vec2 { x: 1300., y: 15. })
+            .ui(&mut root_ui());
+    } else if game.spectating {
+        let followed = game
+            .following
+            .and_then(|id| game.display_state.players.get(&id))
+            .map(|player| format!(", following {}", player.name))
+            .unwrap_or_default();
+        Label::new(format!("Spectating{followed}"))
+            .position(Vec2 { x: 1300., y: 15. })
+            .ui(&mut root_ui());
+    }
+
+    for (i, room) in game.rooms.iter().take(9).enumerate() {
+        let capacity = room
+            .max_players
+            .map(|max_players| format!("/{max_players}"))
+            .unwrap_or_default();
+        Label::new(format!(
+            "{}. {} ({}{}, {} watching)",
+            i + 1,
+            room.id,
+            room.players,
+            capacity,
+            room.spectators
+        ))
+        .position(Vec2 {
+            x: 1050.,
+            y: 45. + i as f32 * 28.,
+        })
+        .ui(&mut root_ui());
+    }
+
     change_style(*prediction, active_skin, inactive_skin);
     Label::new("Prediction".to_string())
         .position(Vec2 { x: 200., y: 15. })
@@ -36,12 +79,105 @@ pub fn draw_ui(game: &mut Game, label_skin: &Skin, active_skin: &Skin, inactive_
         .position(Vec2 { x: 350., y: 15. })
         .ui(&mut root_ui());
 
-    change_style(*interpolation, active_skin, inactive_skin);
+    let interpolation = if game.spectating {
+        game.spectator_settings.interpolation
+    } else {
+        *interpolation
+    };
+    change_style(interpolation, active_skin, inactive_skin);
     Label::new("Interpolation".to_string())
         .position(Vec2 { x: 550., y: 15. })
         .ui(&mut root_ui());
 }
 
+/// Draw the network and netcode metrics as a debug overlay
+pub fn draw_net_stats(stats: &NetStats, label_skin: &Skin) {
+    root_ui().pop_skin();
+    root_ui().push_skin(label_skin);
+
+    let lines = [
+        format!(
+            "Sent: {} msg/s, {} B/s",
+            stats.messages_sent_per_second, stats.bytes_sent_per_second
+        ),
+        format!(
+            "Received: {} msg/s, {} B/s",
+            stats.messages_received_per_second, stats.bytes_received_per_second
+        ),
+        format!(
+            "Snapshots: {}/s, jitter {:.1}ms",
+            stats.snapshots_per_second, stats.snapshot_jitter_ms
+        ),
+        format!(
+            "Last correction: {:.3} units",
+            stats.last_correction().unwrap_or(0.)
+        ),
+        format!(
+            "Interpolation t: {:.2}, extrapolated {:.0}ms",
+            stats.interpolation_t, stats.extrapolation_ms
+        ),
+        format!(
+            "Dropped: {}, late: {}",
+            stats.dropped_packets, stats.late_packets
+        ),
+    ];
+
+    for (i, line) in lines.into_iter().enumerate() {
+        Label::new(line)
+            .position(Vec2 {
+                x: 15.,
+                y: 60. + i as f32 * 28.,
+            })
+            .ui(&mut root_ui());
+    }
+}
+
+/// Draw the players' scores in the tag game mode, highest first, marking the player that is it
+pub fn draw_scores(state: &State, label_skin: &Skin) {
+    root_ui().pop_skin();
+    root_ui().push_skin(label_skin);
+
+    let mut players = state.players.values().collect::<Vec<_>>();
+    players.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+    let lines = std::iter::once("Scores".to_string()).chain(players.into_iter().map(|player| {
+        let it = if state.it == Some(player.id) {
+            " (it)"
+        } else {
+            ""
+        };
+        format!("{}: {:.1}s{it}", player.name, player.score)
+    }));
+
+    for (i, line) in lines.enumerate() {
+        Label::new(line)
+            .position(Vec2 {
+                x: 1300.,
+                y: 45. + i as f32 * 28.,
+            })
+            .ui(&mut root_ui());
+    }
+}
+
+/// Draw why the connection to the server was closed, if it was
+pub fn draw_connection_closed(connection: &Connection, skin: &Skin) {
+    let Connection::Closed(notice) = connection else {
+        return;
+    };
+    let message = match notice {
+        Some(notice) => notice.to_string(),
+        None => "Disconnected from the server.".to_string(),
+    };
+
+    root_ui().push_skin(skin);
+    Label::new(message)
+        .position(Vec2 {
+            x: screen_width() / 2. - 250.,
+            y: screen_height() / 2. - 100.,
+        })
+        .ui(&mut root_ui());
+    root_ui().pop_skin();
+}
+
 /// Changes the game's skin to apply some styling based on a condition.
 /// Removes the current skin before pushing the replacement.
 fn change_style(condition: bool, active_skin: &Skin, inactive_skin: &Skin) {
@@ -0,0 +1,191 @@
+//! AI-controlled players that live in the game state and are moved on every tick.
+//!
+//! AI players are regular [Player]s, so clients cannot tell them apart from human players.
+//! Their behaviour only depends on the state and the tick times, so replays reproduce it.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    event::{JoinRejectReason, PlayerPreferences},
+    state::name_key,
+    State,
+};
+
+/// Distance from its spawn point an AI player patrols up to, in units
+const PATROL_RANGE: f64 = 4.0;
+
+/// Distance an AI player keeps from the player it follows, in units
+const FOLLOW_DISTANCE: f64 = 1.5;
+
+/// Average number of jumps per second of a jumping AI player
+const JUMPS_PER_SECOND: f64 = 0.5;
+
+/// Fraction of the world's max speed AI players move at
+const SPEED_FACTOR: f64 = 0.8;
+
+/// Longest time an AI player moves for in a single tick, so a stalled tick loop does not
+/// teleport it
+const MAX_TICK_SECONDS: f64 = 1.0;
+
+/// Represents all possible errors that can occur when configuring AI players
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum AiError {
+    #[error("[ERROR - AI] Unknown AI behaviour '{0}', expected 'patrol', 'follow' or 'jump'.")]
+    UnknownBehaviour(String),
+}
+
+/// What an AI player does on every tick
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AiBehaviour {
+    /// Walk back and forth around its spawn point
+    Patrol,
+    /// Walk towards the nearest human player
+    Follow,
+    /// Stand still and jump at random
+    Jump,
+}
+
+impl FromStr for AiBehaviour {
+    type Err = AiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "patrol" => Ok(Self::Patrol),
+            "follow" => Ok(Self::Follow),
+            "jump" => Ok(Self::Jump),
+            _ => Err(AiError::UnknownBehaviour(s.to_string())),
+        }
+    }
+}
+
+/// Controller of an AI player, kept by the state next to the player it moves
+#[derive(Debug, Clone)]
+pub(crate) struct AiController {
+    behaviour: AiBehaviour,
+    origin: f64,
+    /// -1 when walking left, 1 when walking right
+    direction: f64,
+    /// Seed of the controller's random numbers, so replays jump at the same times
+    seed: u64,
+}
+
+impl AiController {
+    /// Random number in 0..1, using xorshift
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl State {
+    /// Adds an AI player with the given behaviour, returning its ID. The player is named
+    /// `AI <n>`, with the lowest n not used by a player in the game or returning to it.
+    /// Returns [JoinRejectReason::Full] if the game has reached its max number of players.
+    pub fn spawn_ai(&mut self, behaviour: AiBehaviour) -> Result<usize, JoinRejectReason> {
+        if let Some(max_players) = self.config.max_players {
+            if self.players.len() >= max_players {
+                return Err(JoinRejectReason::Full { max_players });
+            }
+        }
+        let mut n = 1;
+        let name = loop {
+            let name = format!("AI {n}");
+            let key = name_key(&name);
+            let taken = self.returning.contains_key(&key)
+                || self
+                    .players
+                    .values()
+                    .any(|player| name_key(&player.name) == key);
+            if !taken {
+                break name;
+            }
+            n += 1;
+        };
+
+        let id = self.add_player(name, PlayerPreferences::default());
+        self.ai.insert(
+            id,
+            AiController {
+                behaviour,
+                origin: self.players[&id].x,
+                direction: 1.0,
+                seed: (id as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Checks if the player is controlled by the server
+    pub fn is_ai(&self, player_id: usize) -> bool {
+        self.ai.contains_key(&player_id)
+    }
+
+    /// Number of players controlled by the server
+    pub fn ai_count(&self) -> usize {
+        self.ai.len()
+    }
+
+    /// Moves every AI player for the time elapsed since the last tick, up to [at]
+    pub(crate) fn ai_tick(&mut self, at: DateTime<Utc>) {
+        let seconds = (at - self.timestamp)
+            .as_seconds_f64()
+            .clamp(0.0, MAX_TICK_SECONDS);
+        let step = self.config.max_units_per_second * SPEED_FACTOR * seconds;
+
+        let mut ids = self.ai.keys().copied().collect::<Vec<_>>();
+        // Controllers are moved in a fixed order, so replays draw the same random numbers
+        ids.sort_unstable();
+        for id in ids {
+            let humans = self
+                .players
+                .values()
+                .filter(|player| !self.ai.contains_key(&player.id))
+                .map(|player| player.x)
+                .collect::<Vec<_>>();
+            let (Some(player), Some(controller)) =
+                (self.players.get_mut(&id), self.ai.get_mut(&id))
+            else {
+                continue;
+            };
+            let x = player.x;
+            let nearest_human = humans
+                .into_iter()
+                .min_by(|a, b| (a - x).abs().total_cmp(&(b - x).abs()));
+
+            let delta_x = match controller.behaviour {
+                AiBehaviour::Patrol => {
+                    let offset = x - controller.origin;
+                    if offset >= PATROL_RANGE {
+                        controller.direction = -1.0;
+                    } else if offset <= -PATROL_RANGE {
+                        controller.direction = 1.0;
+                    }
+                    controller.direction * step
+                }
+                AiBehaviour::Follow => match nearest_human {
+                    Some(target) if (target - x).abs() > FOLLOW_DISTANCE => {
+                        let gap = target - x;
+                        gap.signum() * step.min(gap.abs() - FOLLOW_DISTANCE)
+                    }
+                    _ => 0.0,
+                },
+                AiBehaviour::Jump => 0.0,
+            };
+            let jump = controller.behaviour == AiBehaviour::Jump
+                && controller.random() < JUMPS_PER_SECOND * seconds;
+
+            player.x += delta_x;
+            if jump && player.y_at(at) <= 0.0 {
+                player.last_jump_at = Some(at);
+            }
+            self.record_sample(id, at);
+            self.push_from(id, x, at);
+        }
+    }
+}
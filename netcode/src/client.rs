@@ -1,33 +1,122 @@
 //! Handles client side state updates with reconciliation, interpolation and prediction.
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::{
         mpsc::{channel, Receiver, Sender},
         Arc, Mutex,
     },
     thread,
+    time::Instant,
 };
 
-use chrono::{TimeDelta, Utc};
-use rust_socketio::{client::Client, ClientBuilder, Payload};
+use chrono::{DateTime, TimeDelta, Utc};
+use rust_socketio::{client::Client, ClientBuilder, Event, Payload};
+use serde::de::DeserializeOwned;
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
 
 use crate::{
-    event::{JoinResponse, PlayerAction},
-    state::Player,
-    Action, State, ACTION_CHANNEL, ERROR_CHANNEL, JOIN_CHANNEL, STATE_CHANNEL,
+    collision,
+    event::{
+        ChatMessage, DisconnectNotice, JoinRejection, JoinResponse, PlayerAction,
+        PlayerPreferences, RoomId, RoomInfo, RoomResponse,
+    },
+    state::{Player, WorldConfig},
+    tag::{self, GameMode},
+    Action, State, ACTION_CHANNEL, CHAT_CHANNEL, DEFAULT_ROOM, DISCONNECT_NOTICE_CHANNEL,
+    ERROR_CHANNEL, JOIN_CHANNEL, JOIN_REJECTED_CHANNEL, PING_CHANNEL, ROOM_CHANNEL, STATE_CHANNEL,
 };
 
+mod error;
+mod events;
+mod stats;
+
+pub use error::GameError;
+pub use events::GameEvent;
+pub use stats::NetStats;
+use stats::{SharedTraffic, StatsCollector};
+
+/// Outcome of a join request, as answered by the server
+type JoinResult = Result<JoinResponse, JoinRejection>;
+
+/// State of the connection to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Connection {
+    /// Waiting for the server to accept the connection. The server drops connections that
+    /// send actions before then
+    Connecting,
+    Connected,
+    /// The connection was closed, with the reason given by the server if it gave one
+    Closed(Option<DisconnectNotice>),
+}
+
+/// Address of the server used by [Game::new]
+pub const DEFAULT_SERVER_ADDRESS: &str = "http://localhost:7878";
+
+/// Max number of chat messages kept in [Game::chat]
+pub const CHAT_HISTORY_LENGTH: usize = 100;
+
+/// Time to wait for the server to pass the tag before tagging again, on top of the ping
+const TAG_RETRY: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Interpolation settings used while spectating, separate from the player's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectatorSettings {
+    pub interpolation: bool,
+    /// Milliseconds the other players are rendered behind, on top of half the ping.
+    /// Spectators have no input to respond to, so they can trade latency for smoothness
+    pub delay_ms: u64,
+}
+
+impl Default for SpectatorSettings {
+    fn default() -> Self {
+        Self {
+            interpolation: true,
+            delay_ms: 100,
+        }
+    }
+}
+
 /// Game state that is mutated through the lifecycle of the client.
 pub struct Game {
     state_receiver: Receiver<State>,
-    join_receiver: Receiver<JoinResponse>,
+    join_receiver: Receiver<JoinResult>,
+    room_receiver: Receiver<RoomResponse>,
+    ping_receiver: Receiver<DateTime<Utc>>,
+    chat_receiver: Receiver<ChatMessage>,
+    event_sender: Sender<GameEvent>,
+    event_receiver: Receiver<GameEvent>,
+    events: VecDeque<GameEvent>,
     pub local_state: State,
     target_state: State,
     previous_state: State,
+    last_snapshot_at: Option<DateTime<Utc>>,
     pub display_state: State,
+    /// Server time the other players in [Game::display_state] are shown at, sent with tags so
+    /// the server can rewind them to check the tag
+    pub display_time: DateTime<Utc>,
+    /// When the last tag was sent, while waiting for the server to pass the tag
+    tag_sent_at: Option<Instant>,
+    /// When the local player was seen becoming it
+    it_since: Option<Instant>,
     pub player_idx: Option<usize>,
+    /// Position in the room's join queue while waiting for a free player slot
+    pub queue_position: Option<usize>,
+    /// Whether the client watches the room's game without a player
+    pub spectating: bool,
+    /// Player the spectator's camera is locked onto
+    pub following: Option<usize>,
+    pub spectator_settings: SpectatorSettings,
+    /// Room the client is currently in
+    pub room_id: RoomId,
+    /// Rooms on the server, as of the last call to [Game::list_rooms]
+    pub rooms: Vec<RoomInfo>,
+    /// Chat messages received in the current room, oldest first
+    pub chat: VecDeque<ChatMessage>,
+    /// Rules of the world, as received from the server when joining
+    pub config: WorldConfig,
+    pub connection: Connection,
     client: Client,
     pub unacknowledged: HashMap<Uuid, PlayerAction>,
     simulated_ping: Arc<Mutex<u64>>,
@@ -35,59 +124,182 @@ pub struct Game {
     pub prediction: bool,
     pub reconciliation: bool,
     pub interpolation: bool,
+    stats: StatsCollector,
 }
 
-impl Default for Game {
-    fn default() -> Self {
-        Self::new()
+impl Game {
+    /// Creates a game state with default values, connected to [DEFAULT_SERVER_ADDRESS]
+    pub fn new() -> Result<Self, GameError> {
+        Self::connect(DEFAULT_SERVER_ADDRESS)
     }
-}
 
-impl Game {
-    /// Creates a game state with default values
-    pub fn new() -> Self {
+    /// Creates a game state with default values, connected to the server at [address]
+    pub fn connect(address: &str) -> Result<Self, GameError> {
         let (state_sender, state_receiver) = channel::<State>();
-        let (join_sender, join_receiver) = channel::<JoinResponse>();
+        let (join_sender, join_receiver) = channel::<JoinResult>();
+        let (room_sender, room_receiver) = channel::<RoomResponse>();
+        let (ping_sender, ping_receiver) = channel::<DateTime<Utc>>();
+        let (chat_sender, chat_receiver) = channel::<ChatMessage>();
+        let (event_sender, event_receiver) = channel::<GameEvent>();
 
         let simulated_ping = Arc::new(Mutex::new(250));
+        let stats = StatsCollector::default();
 
-        Self {
+        let client = build_netcode_client(
+            address,
+            state_sender,
+            join_sender,
+            room_sender,
+            ping_sender,
+            chat_sender,
+            HandlerContext {
+                ping: simulated_ping.clone(),
+                traffic: stats.traffic.clone(),
+                events: event_sender.clone(),
+            },
+        )?;
+        info!(address, "connected to the server");
+
+        Ok(Self {
             state_receiver,
             join_receiver,
+            room_receiver,
+            ping_receiver,
+            chat_receiver,
+            event_sender,
+            event_receiver,
+            events: VecDeque::new(),
             unacknowledged: HashMap::new(),
             local_state: Default::default(),
             previous_state: Default::default(),
+            last_snapshot_at: None,
             target_state: Default::default(),
             display_state: Default::default(),
+            display_time: Utc::now(),
+            tag_sent_at: None,
+            it_since: None,
             player_idx: None,
-            client: build_netcode_client(state_sender, join_sender, simulated_ping.clone()),
+            queue_position: None,
+            spectating: false,
+            following: None,
+            spectator_settings: SpectatorSettings::default(),
+            room_id: DEFAULT_ROOM.to_string(),
+            rooms: Vec::new(),
+            chat: VecDeque::new(),
+            config: WorldConfig::default(),
+            connection: Connection::Connecting,
+            client,
             simulated_ping,
             ping_cache: 0,
             prediction: true,
             reconciliation: true,
             interpolation: true,
+            stats,
+        })
+    }
+
+    /// Join the server-side game under a display name.
+    /// Returns [GameError::AlreadyJoined] if the local player has already joined.
+    pub fn join(&self, name: &str, preferences: PlayerPreferences) -> Result<(), GameError> {
+        if let Some(player_idx) = self.player_idx {
+            return Err(GameError::AlreadyJoined(player_idx));
         }
+        emit_action(
+            &self.client,
+            &self.stats.traffic,
+            &Action::player_join(name, preferences),
+        )
     }
 
-    /// Join the server-side game.
-    /// Make sure to only call this function once, as any future calls result in multiple sessions.
-    pub fn join(&self) {
-        if self
-            .client
-            .emit(
-                ACTION_CHANNEL,
-                Payload::Text(vec![serde_json::to_value(&Action::Join).unwrap()]),
-            )
-            .is_err()
-        {
-            eprintln!("Failed to join the game");
+    /// Watch the room's game without joining it. Spectators are not bound by the room's player
+    /// cap. Returns [GameError::AlreadyJoined] if the local player has already joined.
+    pub fn spectate(&self) -> Result<(), GameError> {
+        if let Some(player_idx) = self.player_idx {
+            return Err(GameError::AlreadyJoined(player_idx));
+        }
+        emit_action(&self.client, &self.stats.traffic, &Action::Spectate)
+    }
+
+    /// Lock the spectator's camera onto the next player, by id, or back to the whole world
+    /// after the last one.
+    pub fn follow_next(&mut self) {
+        let mut ids = self
+            .display_state
+            .players
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        self.following = match self.following {
+            None => ids.first().copied(),
+            Some(following) => ids.into_iter().find(|id| *id > following),
         };
     }
 
+    /// Create a new room on the server and move into it, leaving the current room.
+    /// The room allows at most [max_players] players, or the server's default if not set.
+    pub fn create_room(&self, max_players: Option<usize>) -> Result<(), GameError> {
+        emit_action(
+            &self.client,
+            &self.stats.traffic,
+            &Action::CreateRoom { max_players },
+        )
+    }
+
+    /// Request the list of rooms on the server. The list is available in [Game::rooms] once
+    /// [GameEvent::RoomsListed] is received.
+    pub fn list_rooms(&self) -> Result<(), GameError> {
+        emit_action(&self.client, &self.stats.traffic, &Action::ListRooms)
+    }
+
+    /// Move into the room with the given id, leaving the current room.
+    pub fn join_room(&self, room_id: RoomId) -> Result<(), GameError> {
+        emit_action(
+            &self.client,
+            &self.stats.traffic,
+            &Action::JoinRoom { room_id },
+        )
+    }
+
+    /// Get a snapshot of the network and netcode metrics of this client.
+    pub fn net_stats(&mut self) -> NetStats {
+        self.stats.snapshot(Instant::now())
+    }
+
     /// Tick the game's state. Should be called every frame.
     pub fn update(&mut self) {
+        self.room_update();
         self.state_update();
+        self.tag_update();
         self.join_update();
+        self.ping_update();
+        self.chat_update();
+        self.connection_update();
+    }
+
+    /// Drains the events sent by the connection's handlers, keeping track of the connection
+    fn connection_update(&mut self) {
+        for event in self.event_receiver.try_iter() {
+            match &event {
+                GameEvent::DisconnectNotice(notice) => {
+                    self.connection = Connection::Closed(Some(notice.clone()));
+                }
+                GameEvent::Connected => self.connection = Connection::Connected,
+                GameEvent::Disconnected
+                    if !matches!(self.connection, Connection::Closed(Some(_))) =>
+                {
+                    self.connection = Connection::Closed(None);
+                }
+                _ => {}
+            }
+            self.events.push_back(event);
+        }
+    }
+
+    /// Drain the events that happened since the last call.
+    /// Should be called every frame, as events are buffered until drained.
+    pub fn poll_events(&mut self) -> impl Iterator<Item = GameEvent> + '_ {
+        self.events.drain(..)
     }
 
     /// Handles calculating other player's current coordinates based on the current state.
@@ -95,8 +307,24 @@ impl Game {
         // Find time the lerping value, t, for interpolation using previously obtained states
         let prev = self.previous_state.timestamp;
         let target = self.target_state.timestamp;
-        let curr = Utc::now() - TimeDelta::milliseconds((self.ping_cache / 2) as i64);
-        let t = (curr - target).as_seconds_f64() / (target - prev).as_seconds_f64();
+        let mut delay_ms = self.ping_cache / 2;
+        if self.spectating {
+            delay_ms += self.spectator_settings.delay_ms;
+        }
+        let curr = Utc::now() - TimeDelta::milliseconds(delay_ms as i64);
+        let span = (target - prev).as_seconds_f64();
+        // States from the same server tick have nothing to interpolate between
+        let t = if span > 0.0 {
+            (curr - target).as_seconds_f64() / span
+        } else {
+            1.0
+        };
+
+        self.display_time = prev + TimeDelta::microseconds((t * span * 1e6) as i64);
+
+        // Anything past the target state is extrapolated rather than interpolated
+        let extrapolation_ms = (curr - target).as_seconds_f64().max(0.0) * 1000.0;
+        self.stats.record_interpolation(t, extrapolation_ms);
 
         // Due to prediction and reconciliation we will handle own player differently
         let player_id = self.player_idx.unwrap_or(usize::MAX);
@@ -131,9 +359,8 @@ impl Game {
                 (
                     *tar_player_id,
                     Player {
-                        id: *tar_player_id,
                         x,
-                        last_jump_at: tar_player.last_jump_at,
+                        ..tar_player.clone()
                     },
                 )
             })
@@ -145,6 +372,13 @@ impl Game {
             // Add the current reconciled/predicted player's position to the list of lerped players
             new_curr_players.insert(player_id, display_player.clone());
         }
+        // Predict the pushes given by the player's own moves, which the other players' positions
+        // only show once the server's states catch up
+        if self.prediction && self.config.collision {
+            if let Some(from) = new_curr_players.get(&player_id).map(|player| player.x) {
+                collision::push_players(&mut new_curr_players, player_id, from, Utc::now());
+            }
+        }
         // Update the display state with the new lerped players
         self.display_state.players = new_curr_players;
     }
@@ -159,6 +393,24 @@ impl Game {
     /// Handles updating the state of an active game.
     fn state_update(&mut self) {
         for server_state in self.state_receiver.try_iter() {
+            // Discard snapshots that arrive after a newer one has been applied
+            if self
+                .last_snapshot_at
+                .is_some_and(|last| server_state.timestamp < last)
+            {
+                self.stats.record_late();
+                trace!(timestamp = %server_state.timestamp, "discarded late snapshot");
+                continue;
+            }
+            self.last_snapshot_at = Some(server_state.timestamp);
+            self.stats.record_snapshot(Instant::now());
+            Self::diff_players(
+                &self.target_state,
+                &server_state,
+                self.player_idx,
+                &mut self.events,
+            );
+
             self.previous_state = self.target_state.clone();
             self.target_state = server_state.clone();
 
@@ -168,7 +420,7 @@ impl Game {
             }
 
             // Get the current player
-            let current_player = match self.get_player() {
+            let mut current_player = match self.get_player() {
                 Some(player) => player.clone(),
                 None => {
                     // Update local and display state and continue
@@ -178,6 +430,17 @@ impl Game {
                 }
             };
 
+            // Movement of the player acknowledged by this state
+            let acknowledged_x_diff = self
+                .unacknowledged
+                .iter()
+                .filter(|(id, _)| server_state.acknowledged.contains(*id))
+                .map(|(_, action)| match action {
+                    PlayerAction::Move { delta_x, id: _ } => *delta_x,
+                    PlayerAction::Jump { at: _ } | PlayerAction::Tag { .. } => 0.0,
+                })
+                .sum::<f64>();
+
             // Remove acknowledged actions
             self.unacknowledged
                 .retain(|key, _| !server_state.acknowledged.contains(key));
@@ -190,6 +453,12 @@ impl Game {
                 continue;
             };
 
+            // Any other movement since the previous state, such as another player pushing this
+            // one, is taken from the server rather than corrected back by the next move
+            if let Some(previous_player) = self.previous_state.players.get(&current_player.id) {
+                current_player.x += server_player.x - previous_player.x - acknowledged_x_diff;
+            }
+
             // The server's position plus what has been unacknowledged (sent but not yet processed)
             let reconciled_position = server_player.x + unacknowledged_x_diff;
 
@@ -204,6 +473,7 @@ impl Game {
             // Update the local and display state with the state from the server
             self.local_state = server_state.clone();
             self.display_state = self.previous_state.clone();
+            self.display_time = self.previous_state.timestamp;
 
             // Update the display position and jump time to the reconciled+predicted position
             if let Some(player) = self.local_state.players.get_mut(&current_player.id) {
@@ -224,31 +494,88 @@ impl Game {
             if position_discrepancy.abs() < 0.01 {
                 return;
             }
+            self.stats.record_correction(position_discrepancy);
+            debug!(
+                player_id = current_player.id,
+                delta_x = position_discrepancy,
+                "correction applied"
+            );
+            self.events.push_back(GameEvent::CorrectionApplied {
+                delta_x: position_discrepancy,
+            });
 
-            let action = Action::player_move(current_player.id, position_discrepancy);
+            let action = Action::player_move(position_discrepancy);
 
             // Add the action to the unacknowledged actions and send
             if let Some((id, player_action)) = action.ack_id() {
-                let client_clone = self.client.clone();
-                let ping_cache = self.ping_cache;
-                thread::spawn(move || {
-                    thread::sleep(std::time::Duration::from_millis(ping_cache / 2));
-                    client_clone
-                        .emit(
-                            ACTION_CHANNEL,
-                            Payload::Text(vec![serde_json::to_value(&action).unwrap()]),
-                        )
-                        .unwrap();
-                });
+                self.send_delayed(move || action);
                 self.unacknowledged.insert(id, player_action);
             }
         }
 
-        if self.interpolation {
+        // Stop following players that left the game
+        if self
+            .following
+            .is_some_and(|id| !self.target_state.players.contains_key(&id))
+        {
+            self.following = None;
+        }
+
+        let interpolation = if self.spectating {
+            self.spectator_settings.interpolation
+        } else {
+            self.interpolation
+        };
+        if interpolation {
             self.calculate_interpolation_for_frame();
         }
     }
 
+    /// Queues events for players that joined, left, jumped or became it between the current
+    /// target state and a newly received server state.
+    fn diff_players(
+        previous: &State,
+        server_state: &State,
+        local_id: Option<usize>,
+        events: &mut VecDeque<GameEvent>,
+    ) {
+        if let Some(it) = server_state.it.filter(|it| previous.it != Some(*it)) {
+            events.push_back(GameEvent::Tagged { player_id: it });
+        }
+        let previous = &previous.players;
+        let interest = server_state.interest.clone().unwrap_or_default();
+
+        for (id, player) in server_state.players.iter() {
+            if Some(*id) == local_id {
+                continue;
+            }
+            match previous.get(id) {
+                None if interest.entered.contains(id) => {
+                    events.push_back(GameEvent::PlayerEnteredView { player_id: *id })
+                }
+                None => events.push_back(GameEvent::PlayerJoined { player_id: *id }),
+                Some(prev)
+                    if player.last_jump_at.is_some()
+                        && prev.last_jump_at != player.last_jump_at =>
+                {
+                    events.push_back(GameEvent::PlayerJumped { player_id: *id })
+                }
+                _ => {}
+            }
+        }
+
+        for id in previous.keys() {
+            if Some(*id) == local_id || server_state.players.contains_key(id) {
+                continue;
+            }
+            if interest.left.contains(id) {
+                events.push_back(GameEvent::PlayerLeftView { player_id: *id });
+            } else {
+                events.push_back(GameEvent::PlayerLeft { player_id: *id });
+            }
+        }
+    }
+
     /// Get the total delta_x not accounted for by the received server state.
     fn get_unack_x_diff(&self) -> f64 {
         let mut x_diff = 0.0;
@@ -257,78 +584,245 @@ impl Game {
                 PlayerAction::Move { delta_x, id: _ } => {
                     x_diff += *delta_x;
                 }
-                PlayerAction::Jump { at: _ } => {}
+                PlayerAction::Jump { at: _ } | PlayerAction::Tag { .. } => {}
             }
         }
         x_diff
     }
 
+    /// Tags the first other player the local player touches on screen while it is it, in the
+    /// tag game mode. The server checks the tag against the other player's position at the
+    /// time it is shown at.
+    fn tag_update(&mut self) {
+        let Some(player_idx) = self.player_idx else {
+            return;
+        };
+        if self.config.mode != GameMode::Tag || self.target_state.it != Some(player_idx) {
+            self.it_since = None;
+            return;
+        }
+        // The server's cooldown started before the state saying so arrived
+        let it_since = *self.it_since.get_or_insert_with(Instant::now);
+        let retry = TAG_RETRY + std::time::Duration::from_millis(self.ping_cache);
+        if it_since.elapsed() < tag::TAG_COOLDOWN.to_std().unwrap_or_default()
+            || self
+                .tag_sent_at
+                .is_some_and(|sent_at| sent_at.elapsed() < retry)
+        {
+            return;
+        }
+        let Some(own) = self.display_state.players.get(&player_idx) else {
+            return;
+        };
+
+        let now = Utc::now();
+        let own_position = (own.x, own.y_at(now));
+        let mut others = self
+            .display_state
+            .players
+            .values()
+            .filter(|player| player.id != player_idx)
+            .collect::<Vec<_>>();
+        others.sort_unstable_by_key(|player| player.id);
+        let target = others
+            .into_iter()
+            .find(|player| tag::touching(own_position, (player.x, player.y_at(now)), 0.0))
+            .map(|player| player.id);
+
+        let Some(target) = target else {
+            return;
+        };
+        debug!(target, "tagging");
+        // The server checks the tag from the local player's position as it knows it, so the
+        // movement not sent yet is sent first
+        let mut actions = Vec::new();
+        if let (Some(player), Some(server_player)) = (
+            self.get_player(),
+            self.target_state.players.get(&player_idx),
+        ) {
+            let unsent_x = player.x - (server_player.x + self.get_unack_x_diff());
+            if unsent_x.abs() >= 0.01 {
+                let action = Action::player_move(unsent_x);
+                if let Some((id, player_action)) = action.ack_id() {
+                    self.unacknowledged.insert(id, player_action);
+                }
+                actions.push(action);
+            }
+        }
+        actions.push(Action::player_tag(target, self.display_time));
+        self.send_delayed_batch(move || actions);
+        self.tag_sent_at = Some(Instant::now());
+    }
+
+    /// Checks if the client was moved into another room or received the room list.
+    fn room_update(&mut self) {
+        for room_response in self.room_receiver.try_iter() {
+            match room_response {
+                RoomResponse::Joined { room_id } => {
+                    // Nothing from the previous room carries over
+                    self.player_idx = None;
+                    self.queue_position = None;
+                    self.spectating = false;
+                    self.following = None;
+                    self.unacknowledged.clear();
+                    self.local_state = State::default();
+                    self.target_state = State::default();
+                    self.previous_state = State::default();
+                    self.last_snapshot_at = None;
+                    self.display_state = State::default();
+                    self.chat.clear();
+                    debug!(%room_id, "moved into room");
+                    self.room_id = room_id.clone();
+                    self.events.push_back(GameEvent::RoomJoined { room_id });
+                }
+                RoomResponse::List { rooms } => {
+                    self.rooms = rooms;
+                    self.events.push_back(GameEvent::RoomsListed);
+                }
+                RoomResponse::Spectating { config } => {
+                    debug!(room_id = %self.room_id, "spectating");
+                    self.spectating = true;
+                    self.queue_position = None;
+                    self.config = config;
+                    self.events.push_back(GameEvent::Spectating);
+                }
+            }
+        }
+    }
+
+    /// Adds the received chat messages to the chat history, dropping the oldest ones
+    fn chat_update(&mut self) {
+        for message in self.chat_receiver.try_iter() {
+            if self.chat.len() == CHAT_HISTORY_LENGTH {
+                self.chat.pop_front();
+            }
+            self.chat.push_back(message.clone());
+            self.events.push_back(GameEvent::ChatReceived(message));
+        }
+    }
+
+    /// Answers the server's pings, so the server can measure the round trip time.
+    fn ping_update(&mut self) {
+        for sent_at in self.ping_receiver.try_iter().collect::<Vec<_>>() {
+            self.send_delayed(move || Action::Pong { sent_at });
+        }
+    }
+
     /// Checks if a join response is available to join the game.
     fn join_update(&mut self) {
-        for join_response in self.join_receiver.try_iter() {
+        for join_result in self.join_receiver.try_iter() {
+            let join_response = match join_result {
+                Ok(join_response) => join_response,
+                Err(rejection) => {
+                    debug!(
+                        reason = %rejection.reason,
+                        queue_position = ?rejection.queue_position,
+                        "join rejected"
+                    );
+                    self.queue_position = rejection.queue_position;
+                    self.events.push_back(GameEvent::JoinRejected(rejection));
+                    continue;
+                }
+            };
+            debug!(player_id = join_response.player_id, "joined the game");
+            self.queue_position = None;
+            self.spectating = false;
+            self.following = None;
             self.player_idx = Some(join_response.player_id);
-            self.local_state.players.insert(
-                join_response.player_id,
-                Player::new(join_response.player_id),
-            );
-            self.target_state.players.insert(
+            self.config = join_response.config;
+            self.events.push_back(GameEvent::Joined {
+                player_id: join_response.player_id,
+            });
+            let player = Player::new(
                 join_response.player_id,
-                Player::new(join_response.player_id),
+                join_response.name,
+                join_response.preferences,
             );
+            self.local_state
+                .players
+                .insert(join_response.player_id, player.clone());
+            self.target_state
+                .players
+                .insert(join_response.player_id, player);
         }
     }
 
     /// Make the current player jump.
-    pub fn jump(&mut self) {
-        if let Some(player_idx) = self.player_idx {
-            // Optimistic update
-            self.local_state
-                .players
-                .get_mut(&player_idx)
-                .unwrap()
-                .last_jump_at = Some(chrono::Utc::now());
+    pub fn jump(&mut self) -> Result<(), GameError> {
+        let player_idx = self.player_idx.ok_or(GameError::NotJoined)?;
 
-            if self.prediction {
-                self.display_state
-                    .players
-                    .get_mut(&player_idx)
-                    .unwrap()
-                    .last_jump_at = Some(chrono::Utc::now());
-            }
+        // Optimistic update
+        self.local_state
+            .players
+            .get_mut(&player_idx)
+            .ok_or(GameError::UnknownPlayer(player_idx))?
+            .last_jump_at = Some(chrono::Utc::now());
 
-            let client_clone = self.client.clone();
-
-            // Spawn thread to simulate network delay
-            let ping_cache = self.ping_cache;
-            thread::spawn(move || {
-                thread::sleep(std::time::Duration::from_millis(ping_cache / 2));
-                client_clone
-                    .emit(
-                        ACTION_CHANNEL,
-                        Payload::Text(vec![serde_json::to_value(&Action::Player {
-                            id: player_idx,
-                            action: PlayerAction::Jump { at: Utc::now() },
-                        })
-                        .unwrap()]),
-                    )
-                    .unwrap();
-            });
+        if self.prediction {
+            if let Some(display_player) = self.display_state.players.get_mut(&player_idx) {
+                display_player.last_jump_at = Some(chrono::Utc::now());
+            }
         }
+
+        // The jump time is taken after the delay, as the server would see it
+        self.send_delayed(move || Action::player_jump(Utc::now()));
+        Ok(())
+    }
+
+    /// Sends an action to the server after half the simulated ping has passed.
+    /// The action is built once the delay has passed.
+    fn send_delayed(&self, action: impl FnOnce() -> Action + Send + 'static) {
+        self.send_delayed_batch(move || vec![action()]);
+    }
+
+    /// Sends actions to the server in order after half the simulated ping has passed.
+    /// The actions are built once the delay has passed.
+    fn send_delayed_batch(&self, actions: impl FnOnce() -> Vec<Action> + Send + 'static) {
+        let client_clone = self.client.clone();
+        let traffic = self.stats.traffic.clone();
+        let events = self.event_sender.clone();
+
+        // Spawn thread to simulate network delay
+        let ping_cache = self.ping_cache;
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(ping_cache / 2));
+            for action in actions() {
+                if let Err(err) = emit_action(&client_clone, &traffic, &action) {
+                    warn!(%err, "could not send action");
+                    let _ = events.send(GameEvent::Error(err));
+                }
+            }
+        });
     }
 
     /// Makes the current player move by [delta_x] units
-    pub fn move_player(&mut self, delta_x: f32) {
-        let Some(player_idx) = self.player_idx else {
-            return;
-        };
+    pub fn move_player(&mut self, delta_x: f32) -> Result<(), GameError> {
+        let player_idx = self.player_idx.ok_or(GameError::NotJoined)?;
 
         // Optimistic update
-        self.local_state.players.get_mut(&player_idx).unwrap().x += delta_x as f64;
+        self.local_state
+            .players
+            .get_mut(&player_idx)
+            .ok_or(GameError::UnknownPlayer(player_idx))?
+            .x += delta_x as f64;
         if self.prediction {
             if let Some(display_player) = self.display_state.players.get_mut(&player_idx) {
                 display_player.x += delta_x as f64;
             }
         }
+        Ok(())
+    }
+
+    /// Send a chat message to the other players in the room.
+    /// Returns [GameError::NotJoined] if the local player has not joined, or [GameError::Chat]
+    /// if the message is empty or too long.
+    pub fn send_chat(&self, text: &str) -> Result<(), GameError> {
+        if self.player_idx.is_none() {
+            return Err(GameError::NotJoined);
+        }
+        let text = ChatMessage::validate(text)?.to_string();
+        self.send_delayed(move || Action::Chat { text });
+        Ok(())
     }
 
     /// Update the game's simulated ping amount to check for network issues.
@@ -339,64 +833,183 @@ impl Game {
     }
 }
 
+/// Serializes and sends an action to the server, recording the traffic.
+fn emit_action(client: &Client, traffic: &SharedTraffic, action: &Action) -> Result<(), GameError> {
+    let send_error = |reason: String| GameError::Send {
+        channel: ACTION_CHANNEL,
+        reason,
+    };
+    let payload = serde_json::to_value(action).map_err(|err| send_error(err.to_string()))?;
+    let bytes = payload.to_string().len();
+
+    // Locked once sent, so the handlers recording received traffic never wait on the network
+    let sent_at = Instant::now();
+    let result = client.emit(ACTION_CHANNEL, Payload::Text(vec![payload]));
+    let mut traffic = traffic.lock().unwrap();
+    match result {
+        Ok(()) => {
+            traffic.sent.record(sent_at, bytes);
+            Ok(())
+        }
+        Err(err) => {
+            traffic.dropped += 1;
+            Err(send_error(err.to_string()))
+        }
+    }
+}
+
+/// Handles shared by the socket handlers running on the client's background threads.
+#[derive(Clone)]
+struct HandlerContext {
+    ping: Arc<Mutex<u64>>,
+    traffic: SharedTraffic,
+    events: Sender<GameEvent>,
+}
+
+impl HandlerContext {
+    /// Get the text of a payload sent by the server, recording the traffic.
+    /// Malformed payloads are reported as a [GameEvent::Error] and counted as dropped.
+    fn text(&self, channel: &'static str, payload: Payload) -> Option<String> {
+        let text = match payload {
+            Payload::Text(values) => values
+                .first()
+                .and_then(|value| value.as_str())
+                .map(str::to_string),
+            _ => None,
+        };
+
+        match text {
+            Some(text) => {
+                self.traffic
+                    .lock()
+                    .unwrap()
+                    .received
+                    .record(Instant::now(), text.len());
+                Some(text)
+            }
+            None => {
+                self.malformed(channel, "expected a single text value".to_string());
+                None
+            }
+        }
+    }
+
+    /// Parse a JSON payload sent by the server.
+    /// Malformed payloads are reported as a [GameEvent::Error] and counted as dropped.
+    fn parse<T: DeserializeOwned>(&self, channel: &'static str, payload: Payload) -> Option<T> {
+        let text = self.text(channel, payload)?;
+        serde_json::from_str(&text)
+            .map_err(|err| self.malformed(channel, err.to_string()))
+            .ok()
+    }
+
+    /// Reports a malformed payload through the game's events
+    fn malformed(&self, channel: &'static str, reason: String) {
+        warn!(channel, %reason, "received malformed packet");
+        self.traffic.lock().unwrap().dropped += 1;
+        let _ = self
+            .events
+            .send(GameEvent::Error(GameError::MalformedPacket {
+                channel,
+                reason,
+            }));
+    }
+
+    /// Passes data on to the game after half the simulated ping has passed
+    fn deliver_delayed<T: Send + 'static>(&self, sender: &Sender<T>, data: T) {
+        let sender = sender.clone();
+        let ping = { *self.ping.lock().unwrap() };
+
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(ping / 2));
+            // The receiver is only gone if the game has been dropped
+            let _ = sender.send(data);
+        });
+    }
+}
+
 /// Build a client to handle incomming messages from the server.
 fn build_netcode_client(
+    address: &str,
     state_sender: Sender<State>,
-    join_sender: Sender<JoinResponse>,
-    ping: Arc<Mutex<u64>>,
-) -> Client {
-    let state_ping = ping.clone();
-    let join_ping = ping.clone();
-    ClientBuilder::new("http://localhost:7878")
-        .on(ERROR_CHANNEL, |payload, _| {
-            let Payload::Text(val) = payload else {
-                return;
-            };
-            eprintln!("{}", val.first().unwrap().as_str().unwrap());
+    join_sender: Sender<JoinResult>,
+    room_sender: Sender<RoomResponse>,
+    ping_sender: Sender<DateTime<Utc>>,
+    chat_sender: Sender<ChatMessage>,
+    context: HandlerContext,
+) -> Result<Client, GameError> {
+    let error_context = context.clone();
+    let connect_context = context.clone();
+    let close_context = context.clone();
+    let state_context = context.clone();
+    let join_context = context.clone();
+    let join_rejected_context = context.clone();
+    let join_rejected_sender = join_sender.clone();
+    let room_context = context.clone();
+    let ping_context = context.clone();
+    let chat_context = context.clone();
+    let notice_context = context;
+
+    ClientBuilder::new(address)
+        .on(ERROR_CHANNEL, move |payload, _| {
+            if let Some(message) = error_context.text(ERROR_CHANNEL, payload) {
+                let _ = error_context.events.send(GameEvent::ServerError(message));
+            }
+        })
+        .on(Event::Connect, move |_, _| {
+            debug!("the server accepted the connection");
+            let _ = connect_context.events.send(GameEvent::Connected);
+        })
+        .on(Event::Close, move |_, _| {
+            info!("disconnected from the server");
+            let _ = close_context.events.send(GameEvent::Disconnected);
+        })
+        .on(STATE_CHANNEL, move |payload, _| {
+            if let Some(state) = state_context.parse::<State>(STATE_CHANNEL, payload) {
+                state_context.deliver_delayed(&state_sender, state);
+            }
         })
-        .on(STATE_CHANNEL, move |payload, _| match payload {
-            Payload::Text(text) => {
-                let data =
-                    serde_json::from_str::<State>(text.first().unwrap().clone().as_str().unwrap())
-                        .unwrap();
-                let sender = state_sender.clone();
-                let ping = state_ping.clone();
-                thread::spawn(move || {
-                    let ping = { *ping.lock().unwrap() };
-
-                    thread::sleep(std::time::Duration::from_millis(ping / 2));
-                    sender.send(data).unwrap();
-                });
+        .on(JOIN_CHANNEL, move |payload, _| {
+            if let Some(response) = join_context.parse::<JoinResponse>(JOIN_CHANNEL, payload) {
+                join_context.deliver_delayed(&join_sender, Ok(response));
             }
-            _ => {
-                eprintln!("Received bad payload on state");
+        })
+        .on(JOIN_REJECTED_CHANNEL, move |payload, _| {
+            if let Some(rejection) =
+                join_rejected_context.parse::<JoinRejection>(JOIN_REJECTED_CHANNEL, payload)
+            {
+                join_rejected_context.deliver_delayed(&join_rejected_sender, Err(rejection));
             }
         })
-        .on(JOIN_CHANNEL, move |payload, _| match payload {
-            Payload::Text(text) => {
-                let data = serde_json::from_str::<JoinResponse>(
-                    text.first().unwrap().clone().as_str().unwrap(),
-                )
-                .unwrap();
-                let sender = join_sender.clone();
-                let ping = join_ping.clone();
-
-                let ping = { *ping.lock().unwrap() };
-
-                thread::spawn(move || {
-                    thread::sleep(std::time::Duration::from_millis(ping / 2));
-                    sender.send(data).unwrap();
-                });
+        .on(ROOM_CHANNEL, move |payload, _| {
+            if let Some(response) = room_context.parse::<RoomResponse>(ROOM_CHANNEL, payload) {
+                room_context.deliver_delayed(&room_sender, response);
+            }
+        })
+        .on(PING_CHANNEL, move |payload, _| {
+            if let Some(sent_at) = ping_context.parse::<DateTime<Utc>>(PING_CHANNEL, payload) {
+                ping_context.deliver_delayed(&ping_sender, sent_at);
             }
-            _ => {
-                eprintln!(
-                    "Received non-binary payload on join, received {:?}",
-                    payload
-                );
+        })
+        .on(CHAT_CHANNEL, move |payload, _| {
+            if let Some(message) = chat_context.parse::<ChatMessage>(CHAT_CHANNEL, payload) {
+                chat_context.deliver_delayed(&chat_sender, message);
+            }
+        })
+        .on(DISCONNECT_NOTICE_CHANNEL, move |payload, _| {
+            if let Some(notice) =
+                notice_context.parse::<DisconnectNotice>(DISCONNECT_NOTICE_CHANNEL, payload)
+            {
+                let _ = notice_context
+                    .events
+                    .send(GameEvent::DisconnectNotice(notice));
             }
         })
         .connect()
-        .unwrap()
+        .map_err(|err| GameError::Connection {
+            address: address.to_string(),
+            reason: err.to_string(),
+        })
 }
 
 /// Linear interpolation between two values
@@ -407,10 +1020,61 @@ pub fn lerp(a: f64, b: f64, t: f64) -> f64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::state::{fixture, InterestChanges};
 
     #[test]
     fn test_lerp() {
         assert_eq!(lerp(0., 1., 0.5), 0.5);
         assert_eq!(lerp(0., 10., 0.25), 2.5);
     }
+
+    #[test]
+    fn test_diff_players() {
+        let mut previous = State::default();
+        for name in ["a", "b", "c"] {
+            fixture::join(&mut previous, name);
+        }
+
+        let mut next = previous.clone();
+        next.player_leave(1).unwrap();
+        next.player_jump(2, Utc::now()).unwrap();
+        fixture::join(&mut next, "d");
+
+        let mut events = VecDeque::new();
+        Game::diff_players(&previous, &next, Some(0), &mut events);
+
+        assert!(events.contains(&GameEvent::PlayerLeft { player_id: 1 }));
+        assert!(events.contains(&GameEvent::PlayerJumped { player_id: 2 }));
+        assert!(events.contains(&GameEvent::PlayerJoined { player_id: 3 }));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn test_diff_players_interest() {
+        let mut world = State::default();
+        for name in ["a", "b", "c", "d"] {
+            fixture::join(&mut world, name);
+        }
+        world.players.get_mut(&2).unwrap().x = 100.;
+        world.players.get_mut(&3).unwrap().x = 100.;
+
+        // Player 1 walks away from player 0 while player 2 comes closer, and player 3 leaves
+        let previous = world.view(Some(0), Some(10.));
+        world.players.get_mut(&1).unwrap().x = 100.;
+        world.players.get_mut(&2).unwrap().x = 5.;
+        world.player_leave(3).unwrap();
+        let mut next = world.view(Some(0), Some(10.));
+        next.interest = Some(InterestChanges {
+            entered: vec![2],
+            left: vec![1],
+        });
+
+        let mut events = VecDeque::new();
+        Game::diff_players(&previous, &next, Some(0), &mut events);
+
+        assert_eq!(previous.players.len(), 2);
+        assert!(events.contains(&GameEvent::PlayerLeftView { player_id: 1 }));
+        assert!(events.contains(&GameEvent::PlayerEnteredView { player_id: 2 }));
+        assert_eq!(events.len(), 2);
+    }
 }
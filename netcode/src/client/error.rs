@@ -0,0 +1,30 @@
+//! Errors that can occur on the client side of the game.
+
+use crate::event::ChatError;
+
+/// Represents all possible errors that can occur when running the client
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum GameError {
+    #[error("[ERROR - CONNECTION] Could not connect to the server at {address}: {reason}")]
+    Connection { address: String, reason: String },
+    #[error("[ERROR - SEND] Could not send message on channel '{channel}': {reason}")]
+    Send {
+        channel: &'static str,
+        reason: String,
+    },
+    #[error(
+        "[ERROR - MALFORMED PACKET] Received malformed payload on channel '{channel}': {reason}"
+    )]
+    MalformedPacket {
+        channel: &'static str,
+        reason: String,
+    },
+    #[error("[ERROR - NOT JOINED] The local player has not joined the game.")]
+    NotJoined,
+    #[error("[ERROR - ALREADY JOINED] The local player has already joined with id: {0}.")]
+    AlreadyJoined(usize),
+    #[error("[ERROR - UNKNOWN PLAYER] No local player found with id: {0}.")]
+    UnknownPlayer(usize),
+    #[error(transparent)]
+    Chat(#[from] ChatError),
+}
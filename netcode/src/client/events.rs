@@ -0,0 +1,45 @@
+//! Typed events produced by the client as the game state changes.
+
+use super::GameError;
+use crate::event::{ChatMessage, DisconnectNotice, JoinRejection, RoomId};
+
+/// Something that happened in the game since the events were last drained.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    /// The server accepted the connection, see `Game::connection`
+    Connected,
+    /// The server confirmed that the local player joined the game
+    Joined { player_id: usize },
+    /// The server refused to let the local player join, see `Game::queue_position` if queued
+    JoinRejected(JoinRejection),
+    /// Another player appeared in the game
+    PlayerJoined { player_id: usize },
+    /// Another player left the game
+    PlayerLeft { player_id: usize },
+    /// Another player came within the local player's area of interest
+    PlayerEnteredView { player_id: usize },
+    /// Another player went out of the local player's area of interest, but is still in the game
+    PlayerLeftView { player_id: usize },
+    /// Another player jumped
+    PlayerJumped { player_id: usize },
+    /// A player, possibly the local player, became it in the tag game mode
+    Tagged { player_id: usize },
+    /// Reconciliation corrected the local player's position by [delta_x] units
+    CorrectionApplied { delta_x: f64 },
+    /// The client was moved into a room. The previous room's player and state are gone
+    RoomJoined { room_id: RoomId },
+    /// The list of rooms on the server was received and is available in `Game::rooms`
+    RoomsListed,
+    /// The client is spectating its room's game, see `Game::follow_next` to follow a player
+    Spectating,
+    /// A chat message was received and added to `Game::chat`
+    ChatReceived(ChatMessage),
+    /// The server reported an error
+    ServerError(String),
+    /// A message could not be sent or a packet from the server could not be handled
+    Error(GameError),
+    /// The server is about to close the connection, for the given reason
+    DisconnectNotice(DisconnectNotice),
+    /// The connection to the server was closed
+    Disconnected,
+}
@@ -0,0 +1,204 @@
+//! Network and netcode metrics collected by the client.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Time span used when calculating per-second rates
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of reconciliation corrections kept in the stats history
+const CORRECTION_HISTORY: usize = 32;
+
+/// Smoothing factor for inter-arrival jitter, as used by RTP (RFC 3550)
+const JITTER_SMOOTHING: f64 = 1.0 / 16.0;
+
+/// Snapshot of the client's network and netcode metrics at a point in time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetStats {
+    /// Bytes sent to the server over the last second
+    pub bytes_sent_per_second: usize,
+    /// Bytes received from the server over the last second
+    pub bytes_received_per_second: usize,
+    /// Messages sent to the server over the last second
+    pub messages_sent_per_second: usize,
+    /// Messages received from the server over the last second
+    pub messages_received_per_second: usize,
+    /// State snapshots applied over the last second
+    pub snapshots_per_second: usize,
+    /// Smoothed variation in time between snapshot arrivals, in milliseconds
+    pub snapshot_jitter_ms: f64,
+    /// Magnitude of the most recent reconciliation corrections, oldest first
+    pub corrections: Vec<f64>,
+    /// Lerping value used for interpolation in the last frame
+    pub interpolation_t: f64,
+    /// Time the last frame was extrapolated past the newest snapshot, in milliseconds
+    pub extrapolation_ms: f64,
+    /// Packets that could not be parsed or sent
+    pub dropped_packets: u64,
+    /// Snapshots that arrived after a newer snapshot and were discarded
+    pub late_packets: u64,
+}
+
+impl NetStats {
+    /// Magnitude of the most recent reconciliation correction, if any
+    pub fn last_correction(&self) -> Option<f64> {
+        self.corrections.last().copied()
+    }
+}
+
+/// Counts messages and bytes within a sliding one second window.
+#[derive(Debug, Default)]
+pub(crate) struct RateWindow {
+    entries: VecDeque<(Instant, usize)>,
+}
+
+impl RateWindow {
+    /// Record a single message of [bytes] size
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        self.entries.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    /// Number of messages and bytes recorded within the last second
+    pub fn per_second(&mut self, now: Instant) -> (usize, usize) {
+        self.expire(now);
+        let bytes = self.entries.iter().map(|(_, bytes)| bytes).sum();
+        (self.entries.len(), bytes)
+    }
+
+    /// Removes entries that are older than the window
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.entries.front() {
+            if now.duration_since(*at) <= RATE_WINDOW {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Traffic counters that are shared with the socket handlers running on other threads.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    pub sent: RateWindow,
+    pub received: RateWindow,
+    pub dropped: u64,
+}
+
+/// Handle to the shared traffic counters.
+pub(crate) type SharedTraffic = Arc<Mutex<Traffic>>;
+
+/// Collects metrics through the game's lifecycle and builds [NetStats] snapshots.
+#[derive(Debug, Default)]
+pub(crate) struct StatsCollector {
+    pub traffic: SharedTraffic,
+    snapshots: RateWindow,
+    last_arrival: Option<Instant>,
+    last_interval: Option<f64>,
+    jitter_ms: f64,
+    corrections: VecDeque<f64>,
+    interpolation_t: f64,
+    extrapolation_ms: f64,
+    late: u64,
+}
+
+impl StatsCollector {
+    /// Records the arrival of a state snapshot, updating the arrival rate and jitter
+    pub fn record_snapshot(&mut self, now: Instant) {
+        self.snapshots.record(now, 0);
+
+        if let Some(last_arrival) = self.last_arrival {
+            let interval = now.duration_since(last_arrival).as_secs_f64() * 1000.0;
+            if let Some(last_interval) = self.last_interval {
+                let deviation = (interval - last_interval).abs();
+                self.jitter_ms += (deviation - self.jitter_ms) * JITTER_SMOOTHING;
+            }
+            self.last_interval = Some(interval);
+        }
+        self.last_arrival = Some(now);
+    }
+
+    /// Records a snapshot that was older than the newest one received
+    pub fn record_late(&mut self) {
+        self.late += 1;
+    }
+
+    /// Records the magnitude of a reconciliation correction
+    pub fn record_correction(&mut self, magnitude: f64) {
+        if self.corrections.len() == CORRECTION_HISTORY {
+            self.corrections.pop_front();
+        }
+        self.corrections.push_back(magnitude.abs());
+    }
+
+    /// Records the interpolation values used for the current frame
+    pub fn record_interpolation(&mut self, t: f64, extrapolation_ms: f64) {
+        self.interpolation_t = t;
+        self.extrapolation_ms = extrapolation_ms;
+    }
+
+    /// Builds a snapshot of the metrics collected so far
+    pub fn snapshot(&mut self, now: Instant) -> NetStats {
+        let (snapshots_per_second, _) = self.snapshots.per_second(now);
+        let mut traffic = self.traffic.lock().unwrap();
+        let (messages_sent_per_second, bytes_sent_per_second) = traffic.sent.per_second(now);
+        let (messages_received_per_second, bytes_received_per_second) =
+            traffic.received.per_second(now);
+
+        NetStats {
+            bytes_sent_per_second,
+            bytes_received_per_second,
+            messages_sent_per_second,
+            messages_received_per_second,
+            snapshots_per_second,
+            snapshot_jitter_ms: self.jitter_ms,
+            corrections: self.corrections.iter().copied().collect(),
+            interpolation_t: self.interpolation_t,
+            extrapolation_ms: self.extrapolation_ms,
+            dropped_packets: traffic.dropped,
+            late_packets: self.late,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_window_expires_old_entries() {
+        let start = Instant::now();
+        let mut window = RateWindow::default();
+        window.record(start, 10);
+        window.record(start + Duration::from_millis(500), 20);
+
+        assert_eq!(
+            window.per_second(start + Duration::from_millis(900)),
+            (2, 30)
+        );
+        assert_eq!(
+            window.per_second(start + Duration::from_millis(1200)),
+            (1, 20)
+        );
+        assert_eq!(
+            window.per_second(start + Duration::from_millis(1600)),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn test_steady_snapshots_have_no_jitter() {
+        let start = Instant::now();
+        let mut stats = StatsCollector::default();
+        for i in 0..5 {
+            stats.record_snapshot(start + Duration::from_millis(100 * i));
+        }
+
+        let snapshot = stats.snapshot(start + Duration::from_millis(400));
+        assert_eq!(snapshot.snapshots_per_second, 5);
+        assert_eq!(snapshot.snapshot_jitter_ms, 0.0);
+    }
+}
@@ -0,0 +1,148 @@
+//! Collision between players: players cannot overlap, and push the players in their way.
+//!
+//! The server is authoritative. A moving player always moves by its full delta and pushes the
+//! players it runs into along, who push the players behind them in turn. As pushes add up, two
+//! players pushing each other at the same time end up at the same positions whichever move the
+//! server applies first: equal pushes cancel out. Clients predict their own player's moves and
+//! the pushes it gives, and take the pushes their player receives from the server's state.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::{state::Player, tag::touching, State, PLAYER_HEIGHT, PLAYER_WIDTH};
+
+/// Checks if two players' bodies overlap, given their X and Y coordinates. Bounds are
+/// inclusive, as for [touching]: players standing right next to each other overlap, though
+/// pushing them apart moves neither
+pub fn overlapping(a: (f64, f64), b: (f64, f64)) -> bool {
+    touching(a, b, 0.0)
+}
+
+/// Pushes the players overlapping the pusher out of its way, along with the players they
+/// overlap in turn, returning the IDs of the pushed players.
+/// Players are pushed away from [from], the pusher's X coordinate before it moved, so a player
+/// is pushed in the direction the pusher came at it from. Players at [from] are pushed right
+/// if their ID is greater than the pusher's.
+pub fn push_players(
+    players: &mut HashMap<usize, Player>,
+    pusher: usize,
+    from: f64,
+    at: DateTime<Utc>,
+) -> Vec<usize> {
+    let Some(pusher_player) = players.get(&pusher) else {
+        return Vec::new();
+    };
+    let pusher_position = (pusher_player.x, pusher_player.y_at(at));
+
+    let mut right = Vec::new();
+    let mut left = Vec::new();
+    for player in players.values() {
+        if player.id == pusher {
+            continue;
+        }
+        let entry = (player.id, player.x, player.y_at(at));
+        if player.x > from || (player.x == from && player.id > pusher) {
+            right.push(entry);
+        } else {
+            left.push(entry);
+        }
+    }
+    // Players are pushed nearest first, the lowest ID first on ties
+    right.sort_unstable_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    left.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut pushed = Vec::new();
+    for (side, direction) in [(right, 1.0), (left, -1.0)] {
+        let mut pushers = vec![pusher_position];
+        for (id, x, y) in side {
+            // The player ends up against the furthest pusher it overlaps, including a pusher
+            // that moved past it
+            let target = pushers
+                .iter()
+                .filter(|pusher| (pusher.1 - y).abs() <= PLAYER_HEIGHT)
+                .map(|pusher| pusher.0 + direction * PLAYER_WIDTH)
+                .filter(|target| (target - x) * direction > 0.0)
+                .max_by(|a, b| (a * direction).total_cmp(&(b * direction)));
+            let Some(target) = target else {
+                continue;
+            };
+            players.get_mut(&id).unwrap().x = target;
+            pushers.push((target, y));
+            pushed.push(id);
+        }
+    }
+    pushed
+}
+
+impl State {
+    /// Pushes the players in the way of a player that moved from [from], keeping their
+    /// positions to rewind them when checking tags
+    pub(crate) fn push_from(&mut self, player_id: usize, from: f64, at: DateTime<Utc>) {
+        if !self.config.collision {
+            return;
+        }
+        for pushed in push_players(&mut self.players, player_id, from, at) {
+            self.record_sample(pushed, at);
+        }
+    }
+
+    /// Separates the players that overlap without having moved into each other, such as a
+    /// player landing on another or a player returning where another one stands. Players push
+    /// the others away in the order of their IDs.
+    pub(crate) fn collision_tick(&mut self, at: DateTime<Utc>) {
+        if !self.config.collision {
+            return;
+        }
+        let mut ids = self.players.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        for id in ids {
+            let player = &self.players[&id];
+            let position = (player.x, player.y_at(at));
+            let overlaps = self
+                .players
+                .values()
+                .any(|other| other.id != id && overlapping(position, (other.x, other.y_at(at))));
+            if overlaps {
+                self.push_from(id, position.0, at);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::PlayerAction,
+        state::{fixture, WorldConfig},
+    };
+
+    #[test]
+    fn test_push_is_order_independent() {
+        let mut state = fixture::two_players(WorldConfig::default());
+        // Both players spawn at the same spot, the newest is pushed aside
+        let at = state.timestamp;
+        state.tick_at(at);
+        assert_eq!(state.players[&1].x - state.players[&0].x, PLAYER_WIDTH);
+
+        let step = |delta_x| PlayerAction::Move {
+            delta_x,
+            id: uuid::Uuid::new_v4(),
+        };
+        // Alice pushes Bob along, then both push each other at the same time
+        state.player_action(0, &step(1.0), at).unwrap();
+        assert_eq!(state.players[&1].x, state.players[&0].x + PLAYER_WIDTH);
+        let (alice, bob) = (state.players[&0].x, state.players[&1].x);
+
+        let mut reversed = state.clone();
+        state.player_action(0, &step(0.2), at).unwrap();
+        state.player_action(1, &step(-0.5), at).unwrap();
+        reversed.player_action(1, &step(-0.5), at).unwrap();
+        reversed.player_action(0, &step(0.2), at).unwrap();
+        for state in [state, reversed] {
+            assert!((state.players[&0].x - (alice - 0.3)).abs() < 1e-9);
+            assert!((state.players[&1].x - (bob - 0.3)).abs() < 1e-9);
+        }
+    }
+}
@@ -2,25 +2,62 @@ use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::{
+    state::{Player, WorldConfig},
+    MAX_CHAT_LENGTH,
+};
+
+/// Id of a room hosting an independent match on the server
+pub type RoomId = String;
+
 /// Action that can be sent to the server's [ACTION] channel
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Action {
-    Join,
-    Player { id: usize, action: PlayerAction },
+    Join(JoinRequest),
+    /// Action on the player owned by the sending connection
+    Player {
+        action: PlayerAction,
+    },
+    CreateRoom {
+        max_players: Option<usize>,
+    },
+    ListRooms,
+    JoinRoom {
+        room_id: RoomId,
+    },
+    /// Answer to a ping from the server, echoing the time the ping was sent at
+    Pong {
+        sent_at: chrono::DateTime<Utc>,
+    },
+    /// Chat message to the other clients in the room
+    Chat {
+        text: String,
+    },
+    /// Watch the room's game without joining it, until leaving the room or joining the game
+    Spectate,
 }
 
 impl Action {
-    /// Join the game
-    pub fn player_join() -> Self {
-        Self::Join
+    /// Join the game under a display name
+    pub fn player_join(name: impl Into<String>, preferences: PlayerPreferences) -> Self {
+        Self::Join(JoinRequest {
+            name: name.into(),
+            preferences,
+        })
     }
 
     /// Get the ack_id of the current event, if it has one
     pub fn ack_id(&self) -> Option<(Uuid, PlayerAction)> {
         match self {
-            Action::Join => None,
-            Action::Player { action, id: _ } => match action {
-                PlayerAction::Jump { at: _ } => None,
+            Action::Join(_)
+            | Action::CreateRoom { .. }
+            | Action::ListRooms
+            | Action::JoinRoom { .. }
+            | Action::Pong { .. }
+            | Action::Chat { .. }
+            | Action::Spectate => None,
+            Action::Player { action } => match action {
+                PlayerAction::Jump { at: _ } | PlayerAction::Tag { .. } => None,
                 PlayerAction::Move { delta_x, id } => Some((
                     *id,
                     PlayerAction::Move {
@@ -33,17 +70,22 @@ impl Action {
     }
 
     /// Create a jump action
-    pub fn player_jump(player_id: usize, at: chrono::DateTime<Utc>) -> Self {
+    pub fn player_jump(at: chrono::DateTime<Utc>) -> Self {
         Self::Player {
-            id: player_id,
             action: PlayerAction::Jump { at },
         }
     }
 
+    /// Create an action tagging another player, as it was shown at [seen_at]
+    pub fn player_tag(target: usize, seen_at: chrono::DateTime<Utc>) -> Self {
+        Self::Player {
+            action: PlayerAction::Tag { target, seen_at },
+        }
+    }
+
     /// Create a player movement action
-    pub fn player_move(player_id: usize, delta_x: f64) -> Self {
+    pub fn player_move(delta_x: f64) -> Self {
         Self::Player {
-            id: player_id,
             action: PlayerAction::Move {
                 delta_x,
                 id: Uuid::new_v4(),
@@ -55,19 +97,146 @@ impl Action {
 /// Actions that can be performed on a player that has joined the game
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum PlayerAction {
-    Jump { at: chrono::DateTime<Utc> },
-    Move { delta_x: f64, id: Uuid },
+    Jump {
+        at: chrono::DateTime<Utc>,
+    },
+    Move {
+        delta_x: f64,
+        id: Uuid,
+    },
+    /// Tag another player while being it, as the target was shown at [seen_at] on the client
+    Tag {
+        target: usize,
+        seen_at: chrono::DateTime<Utc>,
+    },
+}
+
+/// Request to join the game, with the name shown above the player
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct JoinRequest {
+    pub name: String,
+    #[serde(default)]
+    pub preferences: PlayerPreferences,
 }
 
-/// Response from joining the game; includes the player's global ID
+/// Optional settings chosen by a player when joining
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+pub struct PlayerPreferences {
+    /// Index of the player's colour in the client's palette. Picked from the player's id if not set
+    pub color: Option<usize>,
+}
+
+/// Response from joining the game; includes the player's global ID, the name it was accepted
+/// under and the world's rules
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct JoinResponse {
     pub player_id: usize,
+    pub name: String,
+    pub preferences: PlayerPreferences,
+    pub config: WorldConfig,
 }
 
 impl JoinResponse {
-    /// Create a join response
-    pub fn new(player_id: usize) -> Self {
-        Self { player_id }
+    /// Create a join response for a player that joined the game
+    pub fn new(player: &Player, config: WorldConfig) -> Self {
+        Self {
+            player_id: player.id,
+            name: player.name.clone(),
+            preferences: player.preferences,
+            config,
+        }
     }
 }
+
+/// Reason for the server to turn down a join request
+#[derive(thiserror::Error, Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum JoinRejectReason {
+    #[error("The game is full ({max_players} players).")]
+    Full { max_players: usize },
+    #[error("Names must be between {min} and {max} characters long.")]
+    NameLength { min: usize, max: usize },
+    #[error("Names can only contain letters, digits, spaces, '-' and '_'.")]
+    NameCharset,
+    #[error("The name {name} is already taken.")]
+    NameTaken { name: String },
+}
+
+/// Response from joining the game when the server turns the join down
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct JoinRejection {
+    pub reason: JoinRejectReason,
+    /// Position in the room's waiting queue, starting at 1, if the client was queued.
+    /// Queued clients receive a [JoinResponse] once a slot frees up.
+    pub queue_position: Option<usize>,
+}
+
+/// Reason sent to the [DISCONNECT_NOTICE_CHANNEL](crate::DISCONNECT_NOTICE_CHANNEL) before the
+/// server closes a client's connection
+#[derive(thiserror::Error, Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum DisconnectNotice {
+    #[error("You were kicked from the server.")]
+    Kicked,
+    #[error("The server shut down: {reason}")]
+    Shutdown { reason: String },
+    #[error("You were disconnected for sending too many actions.")]
+    RateLimited,
+}
+
+/// Chat message broadcast to the [CHAT_CHANNEL](crate::CHAT_CHANNEL) of a room
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub player_id: usize,
+    /// Name of the sender when the message was sent
+    pub name: String,
+    pub text: String,
+    /// Time the server received the message at
+    pub sent_at: chrono::DateTime<Utc>,
+}
+
+/// Represents all possible errors that can occur when sending a chat message
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ChatError {
+    #[error("[ERROR - CHAT] Chat messages cannot be empty.")]
+    Empty,
+    #[error("[ERROR - CHAT] Chat messages are limited to {max} characters.")]
+    TooLong { max: usize },
+    #[error("[ERROR - CHAT] Chat messages are sent too fast.")]
+    RateLimited,
+}
+
+impl ChatMessage {
+    /// Checks the length of a chat message's text, returning it without surrounding whitespace
+    pub fn validate(text: &str) -> Result<&str, ChatError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(ChatError::Empty);
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            return Err(ChatError::TooLong {
+                max: MAX_CHAT_LENGTH,
+            });
+        }
+        Ok(text)
+    }
+}
+
+/// Public information about a room on the server
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub players: usize,
+    pub max_players: Option<usize>,
+    /// Number of sockets spectating the room's game. Not counted in [RoomInfo::players]
+    pub spectators: usize,
+}
+
+/// Response sent to the [ROOM_CHANNEL](crate::ROOM_CHANNEL) after a room action
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum RoomResponse {
+    /// The client was moved into a room
+    Joined { room_id: RoomId },
+    /// All rooms currently hosted by the server
+    List { rooms: Vec<RoomInfo> },
+    /// The client is spectating its room's game, under the given world rules
+    Spectating { config: WorldConfig },
+}
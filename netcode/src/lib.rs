@@ -1,8 +1,16 @@
 //! Netcode handling on server and client to synchronize and minimize lag for multiplayer gaming.
+//!
+//! The shared protocol and simulation build without any client dependencies.
+//! Enable the `client` feature for the client-side `client::Game`.
 
+pub mod ai;
+#[cfg(feature = "client")]
 pub mod client;
+pub mod collision;
 pub mod event;
+pub mod replay;
 pub mod state;
+pub mod tag;
 
 pub use event::Action;
 pub use state::State;
@@ -16,8 +24,43 @@ pub const STATE_CHANNEL: &str = "state";
 /// SocketIO channel name to send join information to the client
 pub const JOIN_CHANNEL: &str = "join";
 
+/// SocketIO channel name to tell the client that its join request was turned down
+pub const JOIN_REJECTED_CHANNEL: &str = "join_rejected";
+
 /// SocketIO channel name to send errors to the client
 pub const ERROR_CHANNEL: &str = "error";
 
+/// SocketIO channel name to send room information to the client
+pub const ROOM_CHANNEL: &str = "room";
+
+/// SocketIO channel name to measure the round trip time to the client, answered with
+/// [Action::Pong]
+pub const PING_CHANNEL: &str = "ping";
+
+/// SocketIO channel name to tell the client why the server is about to close its connection
+pub const DISCONNECT_NOTICE_CHANNEL: &str = "disconnect_notice";
+
+/// SocketIO channel name to broadcast chat messages to the clients in a room
+pub const CHAT_CHANNEL: &str = "chat";
+
+/// Id of the room every client is placed in when connecting
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Min number of characters in a player's name
+pub const MIN_NAME_LENGTH: usize = 1;
+
+/// Max number of characters in a player's name
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Max number of characters in a chat message
+pub const MAX_CHAT_LENGTH: usize = 200;
+
 /// Max number of units traveled per second for a client. Is used to prevent cheating.
 pub const MAX_UNITS_PER_SECOND: f64 = 2.5;
+
+/// Width of a player in units, as drawn by the client
+pub const PLAYER_WIDTH: f64 = 0.75;
+
+/// Height of a player in the units of [Player::y_at](state::Player::y_at), as drawn by the
+/// client
+pub const PLAYER_HEIGHT: f64 = 0.1;
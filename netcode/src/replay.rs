@@ -0,0 +1,350 @@
+//! Recordings of a room's game, which can be re-run offline through [State].
+//!
+//! A replay file holds one JSON object per line: a [ReplayHeader] followed by the recorded
+//! [ReplayEntry]s in the order the server applied them.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ai::AiBehaviour,
+    event::{JoinRejectReason, JoinRequest, PlayerAction, RoomId},
+    state::{Player, SavedState, StateError, WorldConfig},
+    State,
+};
+
+/// Version of the replay format written by this crate. Replays of older versions can still be
+/// read, as each version only adds events and header fields
+pub const REPLAY_VERSION: u32 = 5;
+
+/// Represents all possible errors that can occur when reading, writing or re-running a replay
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayError {
+    #[error("[ERROR - REPLAY] Could not read or write the replay: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("[ERROR - REPLAY] Could not parse line {line} of the replay: {source}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("[ERROR - REPLAY] The replay has no header.")]
+    MissingHeader,
+    #[error("[ERROR - REPLAY] Unsupported replay version {found}, expected at most {expected}.")]
+    Version { found: u32, expected: u32 },
+    #[error("[ERROR - REPLAY] Entry {index} could not be applied: {source}")]
+    State { index: usize, source: StateError },
+    #[error("[ERROR - REPLAY] Entry {index} diverged from the recording: {reason}")]
+    Diverged { index: usize, reason: String },
+}
+
+/// First line of a replay, describing the recorded room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub room_id: RoomId,
+    pub config: WorldConfig,
+    pub started_at: DateTime<Utc>,
+    /// ID given to the next player joining the game, when restored from a saved game
+    #[serde(default)]
+    pub next_player_id: usize,
+    /// Players of the saved game the room was restored from, who can join again
+    #[serde(default)]
+    pub returning: Vec<Player>,
+}
+
+impl ReplayHeader {
+    /// Header of a replay of the given room, starting from its newly created or restored game
+    /// state
+    pub fn new(room_id: RoomId, state: &State) -> Self {
+        let saved = state.save();
+        Self {
+            version: REPLAY_VERSION,
+            room_id,
+            config: state.config,
+            started_at: state.timestamp,
+            next_player_id: saved.next_player_id,
+            returning: saved.players,
+        }
+    }
+}
+
+/// A change to the game state accepted by the server, with the time it was applied at and the
+/// player it applies to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    pub at: DateTime<Utc>,
+    pub player_id: Option<usize>,
+    pub event: ReplayEvent,
+}
+
+impl ReplayEntry {
+    /// Entry applied now
+    pub fn new(player_id: Option<usize>, event: ReplayEvent) -> Self {
+        Self {
+            at: Utc::now(),
+            player_id,
+            event,
+        }
+    }
+}
+
+/// Changes to the game state that are recorded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplayEvent {
+    /// A player joined under the request's name, and was given the entry's player id
+    Join {
+        request: JoinRequest,
+    },
+    Leave,
+    Action {
+        action: PlayerAction,
+    },
+    /// An AI player was added with the given behaviour, and was given the entry's player id
+    SpawnAi {
+        behaviour: AiBehaviour,
+    },
+    /// Tick boundary, moving the AI players and setting the state's timestamp to the entry's
+    /// time
+    Tick,
+    /// The game was replaced by a new one under the same rules, removing every player. The new
+    /// game's timestamp is the entry's time
+    Reset,
+}
+
+/// A recorded game, read from a replay file
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub entries: Vec<ReplayEntry>,
+}
+
+impl Replay {
+    /// Reads a replay, one JSON object per line. Blank lines are skipped.
+    pub fn read(reader: impl BufRead) -> Result<Self, ReplayError> {
+        let mut lines = reader
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()));
+
+        let (index, line) = lines.next().ok_or(ReplayError::MissingHeader)?;
+        let header: ReplayHeader = parse(index, &line?)?;
+        if header.version > REPLAY_VERSION {
+            return Err(ReplayError::Version {
+                found: header.version,
+                expected: REPLAY_VERSION,
+            });
+        }
+
+        let entries = lines
+            .map(|(index, line)| parse(index, &line?))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { header, entries })
+    }
+
+    /// Reads a replay file
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        Self::read(BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Game state the replay starts from, without any player in the game
+    pub fn initial_state(&self) -> State {
+        let mut state = State::restore(SavedState {
+            config: self.header.config,
+            next_player_id: self.header.next_player_id,
+            players: self.header.returning.clone(),
+        });
+        state.timestamp = self.header.started_at;
+        state
+    }
+
+    /// Re-runs every entry of the replay, returning the final game state
+    pub fn run(&self) -> Result<State, ReplayError> {
+        let mut state = self.initial_state();
+        for (index, entry) in self.entries.iter().enumerate() {
+            state.replay(index, entry)?;
+        }
+        Ok(state)
+    }
+}
+
+/// Parses a line of a replay, numbered from 1 in errors
+fn parse<T: for<'de> Deserialize<'de>>(index: usize, line: &str) -> Result<T, ReplayError> {
+    serde_json::from_str(line).map_err(|source| ReplayError::Parse {
+        line: index + 1,
+        source,
+    })
+}
+
+/// Writes a replay, one JSON object per line
+#[derive(Debug)]
+pub struct ReplayWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ReplayWriter<W> {
+    /// Starts a replay by writing its header
+    pub fn new(mut writer: W, header: &ReplayHeader) -> Result<Self, ReplayError> {
+        serde_json::to_writer(&mut writer, header).map_err(std::io::Error::from)?;
+        writer.write_all(b"\n")?;
+        Ok(Self { writer })
+    }
+
+    /// Appends an entry to the replay. Tick boundaries flush the writer, so a replay cut short
+    /// ends on a whole tick.
+    pub fn record(&mut self, entry: &ReplayEntry) -> Result<(), ReplayError> {
+        serde_json::to_writer(&mut self.writer, entry).map_err(std::io::Error::from)?;
+        self.writer.write_all(b"\n")?;
+        if matches!(entry.event, ReplayEvent::Tick) {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the replay, flushing and returning the underlying writer
+    pub fn finish(mut self) -> Result<W, ReplayError> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl State {
+    /// Applies a recorded entry to the state, the way the server applied it.
+    /// The [index] of the entry in the replay is used in errors.
+    pub fn replay(&mut self, index: usize, entry: &ReplayEntry) -> Result<(), ReplayError> {
+        let player_id = || {
+            entry.player_id.ok_or_else(|| ReplayError::Diverged {
+                index,
+                reason: "the entry has no player".to_string(),
+            })
+        };
+        let state_error = |source| ReplayError::State { index, source };
+
+        match &entry.event {
+            ReplayEvent::Join { request } => {
+                check_joined(index, player_id()?, self.player_join(request))?;
+            }
+            ReplayEvent::SpawnAi { behaviour } => {
+                check_joined(index, player_id()?, self.spawn_ai(*behaviour))?;
+            }
+            ReplayEvent::Leave => self.player_leave(player_id()?).map_err(state_error)?,
+            ReplayEvent::Action { action } => self
+                .player_action(player_id()?, action, entry.at)
+                .map_err(state_error)?,
+            ReplayEvent::Tick => self.tick_at(entry.at),
+            ReplayEvent::Reset => {
+                *self = State::new(self.config);
+                self.timestamp = entry.at;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a player joined the replayed game under the id it was given when recorded
+fn check_joined(
+    index: usize,
+    expected: usize,
+    joined: Result<usize, JoinRejectReason>,
+) -> Result<(), ReplayError> {
+    match joined {
+        Ok(id) if id == expected => Ok(()),
+        Ok(id) => Err(ReplayError::Diverged {
+            index,
+            reason: format!("joined as player {id} instead of {expected}"),
+        }),
+        Err(reason) => Err(ReplayError::Diverged {
+            index,
+            reason: reason.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::fixture;
+
+    #[test]
+    fn test_replay_reaches_recorded_state() {
+        let mut live = State::new(WorldConfig::default());
+        let header = ReplayHeader::new("lobby".to_string(), &live);
+        let mut writer = ReplayWriter::new(Vec::new(), &header).unwrap();
+
+        for name in ["Alice", "Bob"] {
+            let request = fixture::join_request(name);
+            let player_id = live.player_join(&request).unwrap();
+            let entry = ReplayEntry::new(Some(player_id), ReplayEvent::Join { request });
+            writer.record(&entry).unwrap();
+        }
+        for behaviour in [AiBehaviour::Patrol, AiBehaviour::Follow, AiBehaviour::Jump] {
+            let player_id = live.spawn_ai(behaviour).unwrap();
+            let entry = ReplayEntry::new(Some(player_id), ReplayEvent::SpawnAi { behaviour });
+            writer.record(&entry).unwrap();
+        }
+        for _ in 0..20 {
+            live.tick_at(fixture::after(live.timestamp, 100));
+            let mut tick = ReplayEntry::new(None, ReplayEvent::Tick);
+            tick.at = live.timestamp;
+            writer.record(&tick).unwrap();
+        }
+
+        let bob_x = live.players[&1].x;
+        let action = PlayerAction::Move {
+            delta_x: 1.5,
+            id: uuid::Uuid::new_v4(),
+        };
+        let entry = ReplayEntry::new(
+            Some(1),
+            ReplayEvent::Action {
+                action: action.clone(),
+            },
+        );
+        live.player_action(1, &action, entry.at).unwrap();
+        writer.record(&entry).unwrap();
+        live.player_leave(0).unwrap();
+        writer
+            .record(&ReplayEntry::new(Some(0), ReplayEvent::Leave))
+            .unwrap();
+
+        let replay = Replay::read(writer.finish().unwrap().as_slice()).unwrap();
+        let replayed = replay.run().unwrap();
+        assert_eq!(replayed.players, live.players);
+        assert_eq!(replayed.timestamp, live.timestamp);
+        assert_eq!(replayed.players[&1].x, bob_x + 1.5);
+    }
+
+    #[test]
+    fn test_restored_players_return_in_replay() {
+        let mut saved = fixture::two_players(WorldConfig::default());
+        saved.players.get_mut(&1).unwrap().x = -3.0;
+        saved.spawn_ai(AiBehaviour::Jump).unwrap();
+
+        let mut live = State::restore(saved.save());
+        assert!(live.players.is_empty());
+        let header = ReplayHeader::new("lobby".to_string(), &live);
+        let mut writer = ReplayWriter::new(Vec::new(), &header).unwrap();
+        assert_eq!(
+            live.player_join(&fixture::join_request("bob")),
+            Err(JoinRejectReason::NameTaken {
+                name: "bob".to_string()
+            })
+        );
+        for name in ["Bob", "Carol"] {
+            let request = fixture::join_request(name);
+            let player_id = live.player_join(&request).unwrap();
+            let entry = ReplayEntry::new(Some(player_id), ReplayEvent::Join { request });
+            writer.record(&entry).unwrap();
+        }
+        assert_eq!(live.players[&1].x, -3.0);
+        assert_eq!(live.players[&1].name, "Bob");
+        assert_eq!(live.players[&3].name, "Carol");
+
+        let replay = Replay::read(writer.finish().unwrap().as_slice()).unwrap();
+        assert_eq!(replay.run().unwrap().players, live.players);
+    }
+}
@@ -1,10 +1,20 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use crate::MAX_UNITS_PER_SECOND;
+use crate::{
+    ai::AiController,
+    event::{JoinRejectReason, JoinRequest, PlayerAction, PlayerPreferences},
+    tag::{GameMode, PlayerSample},
+    MAX_NAME_LENGTH, MAX_UNITS_PER_SECOND, MIN_NAME_LENGTH,
+};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Normalises a player name to compare names, ignoring surrounding whitespace and case
+pub(crate) fn name_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
 /// Server state including position of all players and tick info
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
@@ -12,7 +22,42 @@ pub struct State {
     pub timestamp: DateTime<Utc>,
     #[serde(skip)]
     new_player_id: usize,
+    /// Requests of the receiving client's player processed since the previous state.
+    /// Always empty on the server, which keeps the requests of every player apart
     pub acknowledged: HashSet<Uuid>,
+    /// Requests processed since the last broadcast, by player
+    #[serde(skip)]
+    acks: HashMap<usize, HashSet<Uuid>>,
+    #[serde(skip)]
+    pub config: WorldConfig,
+    /// Changes to the receiving client's area of interest, when the server only sends the
+    /// players around the client's own player
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interest: Option<InterestChanges>,
+    /// Controllers of the AI players, by player id. Never sent, so AI players look like any
+    /// other player to the clients
+    #[serde(skip)]
+    pub(crate) ai: HashMap<usize, AiController>,
+    /// Players of a restored game that have not joined again yet, by [name_key]
+    #[serde(skip)]
+    pub(crate) returning: HashMap<String, Player>,
+    /// Player that is it, in the tag game mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub it: Option<usize>,
+    /// When the player that is it became it
+    #[serde(skip)]
+    pub(crate) it_since: Option<DateTime<Utc>>,
+    /// Recent positions of every player, oldest first, to rewind them when checking tags
+    #[serde(skip)]
+    pub(crate) history: HashMap<usize, VecDeque<PlayerSample>>,
+}
+
+/// Players that entered or left a client's area of interest since the previous state, while
+/// staying in the game
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InterestChanges {
+    pub entered: Vec<usize>,
+    pub left: Vec<usize>,
 }
 
 impl Default for State {
@@ -22,32 +67,186 @@ impl Default for State {
             timestamp: Utc::now(),
             new_player_id: 0,
             acknowledged: HashSet::new(),
+            acks: HashMap::new(),
+            config: WorldConfig::default(),
+            interest: None,
+            ai: HashMap::new(),
+            returning: HashMap::new(),
+            it: None,
+            it_since: None,
+            history: HashMap::new(),
         }
     }
 }
 
+/// Rules of the game world that can be configured by the server
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorldConfig {
+    /// Max number of players in the world at once. Unlimited if [None]
+    pub max_players: Option<usize>,
+    /// Units traveled per second by a moving player. Clients and AI players move at this
+    /// speed, the server does not check the moves of human players against it
+    pub max_units_per_second: f64,
+    #[serde(default)]
+    pub mode: GameMode,
+    /// Whether players collide and push each other. Off when missing, as in replays recorded
+    /// before players collided
+    #[serde(default)]
+    pub collision: bool,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            max_players: None,
+            max_units_per_second: MAX_UNITS_PER_SECOND,
+            mode: GameMode::Free,
+            collision: true,
+        }
+    }
+}
+
+/// A game saved by the server to restore it after a restart. AI players are not saved
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    pub config: WorldConfig,
+    /// ID given to the next player joining the game
+    pub next_player_id: usize,
+    /// Human players of the game, sorted by id
+    pub players: Vec<Player>,
+}
+
 /// Represents all possible errors that can occur when updating the game state
 #[derive(thiserror::Error, Debug)]
 pub enum StateError {
     #[error("[ERROR - UNKNOWN PLAYER] No player found with id: {0}.")]
     UnknownPlayer(usize),
-    #[error("[ERROR - CHEATING] Player moved {units:.5} units in the last {timeframe_seconds:.5} s ({0:.5} unit/s). Expected at most {MAX_UNITS_PER_SECOND} unit/s", units / timeframe_seconds)]
-    Cheating { units: f64, timeframe_seconds: f64 },
+    #[error("[ERROR - CHEATING] Player moved {units:.5} units in the last {timeframe_seconds:.5} s ({0:.5} unit/s). Expected at most {max_units_per_second} unit/s", units / timeframe_seconds)]
+    Cheating {
+        units: f64,
+        timeframe_seconds: f64,
+        max_units_per_second: f64,
+    },
+    #[error("[ERROR - TAG] Player {0} is not it.")]
+    NotIt(usize),
+    #[error("[ERROR - TAG] Player {0} cannot tag itself.")]
+    TagSelf(usize),
+    #[error("[ERROR - TAG] Players cannot tag within {seconds}s of becoming it.")]
+    TagCooldown { seconds: f64 },
+    #[error("[ERROR - TAG] Player {target} was out of reach when tagged, {distance:.3} units away on the X axis.")]
+    TagMissed { target: usize, distance: f64 },
 }
 
 impl State {
+    /// Creates an empty game state with the given rules
+    pub fn new(config: WorldConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a game from a saved one. The saved players are not in the game until they join
+    /// again under the same name, which puts them back at their saved position with their
+    /// saved ID.
+    pub fn restore(saved: SavedState) -> Self {
+        let returning = saved
+            .players
+            .into_iter()
+            .map(|player| {
+                let player = Player {
+                    last_jump_at: None,
+                    ..player
+                };
+                (name_key(&player.name), player)
+            })
+            .collect();
+        Self {
+            new_player_id: saved.next_player_id,
+            returning,
+            ..Self::new(saved.config)
+        }
+    }
+
+    /// Saves the game's rules, ID counter and human players, including the players of a
+    /// restored game that have not returned yet
+    pub fn save(&self) -> SavedState {
+        let mut players = self
+            .players
+            .values()
+            .filter(|player| !self.is_ai(player.id))
+            .chain(self.returning.values())
+            .cloned()
+            .collect::<Vec<_>>();
+        players.sort_unstable_by_key(|player| player.id);
+        SavedState {
+            config: self.config,
+            next_player_id: self.new_player_id,
+            players,
+        }
+    }
+
     /// Update the timestamp of the game's last update
-    pub fn tick(&mut self) -> String {
-        self.timestamp = Utc::now();
-        let message = serde_json::to_string_pretty(self).unwrap();
-        self.clear_ack();
-        message
+    pub fn tick(&mut self) {
+        self.tick_at(Utc::now());
+    }
+
+    /// Moves the AI players, separates overlapping players and scores the tag game for the time
+    /// since the game's last update, then updates the timestamp of the game's last update to
+    /// the given time
+    pub fn tick_at(&mut self, at: DateTime<Utc>) {
+        self.ai_tick(at);
+        self.collision_tick(at);
+        self.tag_tick(at);
+        self.timestamp = at;
+    }
+
+    /// Serializes the state to be sent to clients
+    pub fn message(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
     }
 
-    /// Clears acknowledged requests that are part of the current state,
+    /// Copy of the state as sent to the client controlling the given player, acknowledging
+    /// only that player's requests. With a [interest_radius], only the players within that many
+    /// units of the given player are kept, including the player itself.
+    pub fn view(&self, player_id: Option<usize>, interest_radius: Option<f64>) -> State {
+        let center = player_id
+            .and_then(|player_id| self.players.get(&player_id))
+            .map(|player| player.x);
+        let players = match (center, interest_radius) {
+            (Some(center), Some(radius)) => self
+                .players
+                .iter()
+                .filter(|(id, player)| {
+                    Some(**id) == player_id || (player.x - center).abs() <= radius
+                })
+                .map(|(id, player)| (*id, player.clone()))
+                .collect(),
+            _ => self.players.clone(),
+        };
+        State {
+            players,
+            timestamp: self.timestamp,
+            new_player_id: self.new_player_id,
+            acknowledged: player_id
+                .and_then(|player_id| self.acks.get(&player_id))
+                .cloned()
+                .unwrap_or_default(),
+            acks: HashMap::new(),
+            config: self.config,
+            interest: None,
+            ai: HashMap::new(),
+            returning: HashMap::new(),
+            it: self.it,
+            it_since: None,
+            history: HashMap::new(),
+        }
+    }
+
+    /// Clears acknowledged requests that were sent to the clients,
     /// to ensure no duplicate requests
     pub fn clear_ack(&mut self) {
-        self.acknowledged.clear();
+        self.acks.clear();
     }
 
     /// Get a player by id. Returns [StateError::UnknownPlayer] if the player does not exist.
@@ -57,6 +256,29 @@ impl State {
             .ok_or(StateError::UnknownPlayer(player_id))
     }
 
+    /// Applies an action sent by the client controlling the player, at the time the server
+    /// received it
+    pub fn player_action(
+        &mut self,
+        player_id: usize,
+        action: &PlayerAction,
+        at: DateTime<Utc>,
+    ) -> Result<(), StateError> {
+        match *action {
+            PlayerAction::Jump { at: jump_at } => self.player_jump(player_id, jump_at)?,
+            PlayerAction::Move { delta_x, id } => {
+                let from = self.player(player_id)?.x;
+                self.player_move(player_id, delta_x, id)?;
+                self.push_from(player_id, from, at);
+            }
+            PlayerAction::Tag { target, seen_at } => {
+                return self.player_tag(player_id, target, seen_at, at);
+            }
+        }
+        self.record_sample(player_id, at);
+        Ok(())
+    }
+
     /// Makes a player jump at the specified time.
     /// Time is sent by the frontend to ensure consistent jump animation in real-time.
     pub fn player_jump(&mut self, player_id: usize, at: DateTime<Utc>) -> Result<(), StateError> {
@@ -74,32 +296,137 @@ impl State {
         // let seconds_since_last_update = (Utc::now() - self.timestamp).as_seconds_f64();
         // let x_per_second = delta_x / seconds_since_last_update as f64;
 
-        // if x_per_second.abs() > MAX_UNITS_PER_SECOND {
+        // let max_units_per_second = self.config.max_units_per_second;
+        // if x_per_second.abs() > max_units_per_second {
         //     self.player(player_id)?.x +=
-        //         MAX_UNITS_PER_SECOND * if x_per_second.is_sign_positive() { 1.0 } else { -1.0 };
+        //         max_units_per_second * if x_per_second.is_sign_positive() { 1.0 } else { -1.0 };
 
         //     return Err(StateError::Cheating {
         //         units: delta_x,
         //         timeframe_seconds: seconds_since_last_update,
+        //         max_units_per_second,
         //     });
         // }
 
-        self.acknowledged.insert(ack_id);
         self.player(player_id)?.x += delta_x;
+        self.acks.entry(player_id).or_default().insert(ack_id);
         Ok(())
     }
 
-    /// Makes a player join the game, returning the player's ID
-    pub fn player_join(&mut self) -> usize {
+    /// Makes a player join the game under the requested name, returning the player's ID.
+    /// Once the game has reached its max number of players, the newest AI player leaves to
+    /// make room, as does an AI player holding the requested name. Returns a
+    /// [JoinRejectReason] if the name is invalid or taken, or if the game only has human
+    /// players and has reached its max number of players.
+    /// A player of a restored game joining under its saved name returns where it left off.
+    pub fn player_join(&mut self, request: &JoinRequest) -> Result<usize, JoinRejectReason> {
+        // A human asking for an AI player's name takes that AI player's place
+        let key = name_key(&request.name);
+        let named_ai = self
+            .ai
+            .keys()
+            .copied()
+            .find(|id| name_key(&self.players[id].name) == key);
+        // The game is only changed once the join is sure to be accepted
+        let name = self.validate_name(&request.name, named_ai)?;
+        let replaced = match (named_ai, self.config.max_players) {
+            (Some(id), _) => Some(id),
+            (None, Some(max_players)) if self.players.len() >= max_players => Some(
+                self.ai
+                    .keys()
+                    .max()
+                    .copied()
+                    .ok_or(JoinRejectReason::Full { max_players })?,
+            ),
+            (None, _) => None,
+        };
+        if let Some(id) = replaced {
+            self.player_leave(id)
+                .expect("AI controllers are removed with their player");
+        }
+
+        match self.returning.remove(&key) {
+            Some(player) => {
+                let id = player.id;
+                let player = Player {
+                    name,
+                    preferences: request.preferences,
+                    ..player
+                };
+                self.players.insert(id, player);
+                self.record_sample(id, self.timestamp);
+                Ok(id)
+            }
+            None => Ok(self.add_player(name, request.preferences)),
+        }
+    }
+
+    /// Adds a player to the game with the next player ID, returning the ID
+    pub(crate) fn add_player(&mut self, name: String, preferences: PlayerPreferences) -> usize {
         let id = self.new_player_id;
         self.new_player_id += 1;
-        let player = Player::new(id);
-        self.players.insert(id, player);
+        self.players.insert(id, Player::new(id, name, preferences));
+        self.record_sample(id, self.timestamp);
         id
     }
 
+    /// Checks a requested name's length, charset and uniqueness, ignoring case and the
+    /// [replaced] player. The name of a player of a restored game is only free under its exact
+    /// saved spelling, which makes that player return. Returns the name without its
+    /// surrounding whitespace.
+    fn validate_name(
+        &self,
+        name: &str,
+        replaced: Option<usize>,
+    ) -> Result<String, JoinRejectReason> {
+        let name = name.trim();
+        let length = name.chars().count();
+        if !(MIN_NAME_LENGTH..=MAX_NAME_LENGTH).contains(&length) {
+            return Err(JoinRejectReason::NameLength {
+                min: MIN_NAME_LENGTH,
+                max: MAX_NAME_LENGTH,
+            });
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        {
+            return Err(JoinRejectReason::NameCharset);
+        }
+        let key = name_key(name);
+        let taken = self
+            .players
+            .values()
+            .any(|player| Some(player.id) != replaced && name_key(&player.name) == key)
+            || self
+                .returning
+                .get(&key)
+                .is_some_and(|player| player.name != name);
+        if taken {
+            return Err(JoinRejectReason::NameTaken {
+                name: name.to_string(),
+            });
+        }
+        Ok(name.to_string())
+    }
+
+    /// Checks if the game has reached its max number of players, with no AI player left for
+    /// a human player to replace
+    pub fn is_full(&self) -> bool {
+        self.config
+            .max_players
+            .is_some_and(|max_players| self.players.len() - self.ai.len() >= max_players)
+    }
+
     /// Makes a player leave the game, returning a [StateError] if the player does not exist.
     pub fn player_leave(&mut self, player_id: usize) -> Result<(), StateError> {
+        self.acks.remove(&player_id);
+        self.ai.remove(&player_id);
+        self.history.remove(&player_id);
+        // A new player is picked to be it on the next tick
+        if self.it == Some(player_id) {
+            self.it = None;
+        }
         match self.players.remove(&player_id) {
             Some(_) => Ok(()),
             None => Err(StateError::UnknownPlayer(player_id)),
@@ -107,31 +434,44 @@ impl State {
     }
 }
 
-/// A single player in the game, with id and positional info
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// A single player in the game, with id, name and positional info
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Player {
     pub id: usize,
+    pub name: String,
+    pub preferences: PlayerPreferences,
     pub x: f64,
     pub last_jump_at: Option<chrono::DateTime<Utc>>,
+    /// Seconds spent not being it, in the tag game mode
+    #[serde(default)]
+    pub score: f64,
 }
 
 impl Player {
-    /// Create a player by ID
-    pub fn new(id: usize) -> Self {
+    /// Create a player by ID, with the name shown above it
+    pub fn new(id: usize, name: String, preferences: PlayerPreferences) -> Self {
         Self {
             id,
+            name,
+            preferences,
             x: 5.0,
             last_jump_at: None,
+            score: 0.0,
         }
     }
 
     /// Get the player's current Y coordinate given the player's state
     pub fn y(&self) -> f64 {
+        self.y_at(Utc::now())
+    }
+
+    /// Get the player's Y coordinate at the given time, such as the time shown by a replay
+    pub fn y_at(&self, at: DateTime<Utc>) -> f64 {
         let Some(last_jump_at) = self.last_jump_at else {
             return 0.0;
         };
 
-        let t = (chrono::Utc::now() - last_jump_at).as_seconds_f64();
+        let t = (at - last_jump_at).as_seconds_f64();
 
         if !(0.0..=0.33).contains(&t) {
             0.0
@@ -140,3 +480,74 @@ impl Player {
         }
     }
 }
+
+/// Games shared by the tests of the game's modules and of the server
+#[cfg(any(test, feature = "fixture"))]
+pub mod fixture {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    /// Request to join under the given name, with the default preferences
+    pub fn join_request(name: &str) -> JoinRequest {
+        JoinRequest {
+            name: name.to_string(),
+            preferences: PlayerPreferences::default(),
+        }
+    }
+
+    /// Makes a player join under the given name, returning its ID
+    pub fn join(state: &mut State, name: &str) -> usize {
+        state.player_join(&join_request(name)).unwrap()
+    }
+
+    /// Game with Alice and Bob joined, as players 0 and 1
+    pub fn two_players(config: WorldConfig) -> State {
+        let mut state = State::new(config);
+        join(&mut state, "Alice");
+        join(&mut state, "Bob");
+        state
+    }
+
+    /// Time the given number of milliseconds after [start]
+    pub fn after(start: DateTime<Utc>, ms: i64) -> DateTime<Utc> {
+        start + TimeDelta::milliseconds(ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::AiBehaviour;
+
+    #[test]
+    fn test_rejected_join_keeps_ai_players() {
+        let mut state = State::new(WorldConfig {
+            max_players: Some(1),
+            ..Default::default()
+        });
+        state.spawn_ai(AiBehaviour::Jump).unwrap();
+        // A saved player waits for the AI player's name under another spelling
+        let saved = Player::new(7, "Ai 1".to_string(), PlayerPreferences::default());
+        state.returning.insert(name_key(&saved.name), saved);
+
+        assert_eq!(
+            state.player_join(&fixture::join_request("AI 1")),
+            Err(JoinRejectReason::NameTaken {
+                name: "AI 1".to_string()
+            })
+        );
+        assert_eq!(
+            state.player_join(&fixture::join_request("Al!ce")),
+            Err(JoinRejectReason::NameCharset)
+        );
+        assert_eq!(state.ai_count(), 1);
+
+        // Once the name is free, a human asking for it takes the AI player's place
+        state.returning.clear();
+        let id = state.player_join(&fixture::join_request("ai 1")).unwrap();
+        assert_eq!(state.ai_count(), 0);
+        assert_eq!(state.players.len(), 1);
+        assert_eq!(state.players[&id].name, "ai 1");
+    }
+}
@@ -0,0 +1,270 @@
+//! Tag game mode: one player is "it" and passes the tag by touching another player.
+//!
+//! Clients see the other players in the past, as they interpolate between states. Tags are
+//! checked with lag compensation: the server rewinds the tagged player to the time the tagger
+//! saw it at, using the positions it kept from the last [MAX_REWIND].
+
+use std::str::FromStr;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    state::{Player, StateError},
+    State, PLAYER_HEIGHT, PLAYER_WIDTH,
+};
+
+/// Furthest back in time the server rewinds players to check a tag
+pub const MAX_REWIND: TimeDelta = TimeDelta::seconds(1);
+
+/// Extra distance in units between two players for a tag to still count, covering the time
+/// between two position updates
+const TAG_TOLERANCE: f64 = 0.1;
+
+/// Time after becoming it during which a player cannot tag, so it cannot tag straight back
+pub const TAG_COOLDOWN: TimeDelta = TimeDelta::seconds(1);
+
+/// Represents all possible errors that can occur when configuring the game mode
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum GameModeError {
+    #[error("[ERROR - GAME MODE] Unknown game mode '{0}', expected 'free' or 'tag'.")]
+    Unknown(String),
+}
+
+/// Objective of the game
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameMode {
+    /// Players walk and jump with no objective
+    #[default]
+    Free,
+    /// One player is it and passes the tag by touching another player. Players score the
+    /// seconds they spend not being it
+    Tag,
+}
+
+impl FromStr for GameMode {
+    type Err = GameModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free" => Ok(Self::Free),
+            "tag" => Ok(Self::Tag),
+            _ => Err(GameModeError::Unknown(s.to_string())),
+        }
+    }
+}
+
+/// Position of a player from the time it was applied at, until the next sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PlayerSample {
+    at: DateTime<Utc>,
+    x: f64,
+    last_jump_at: Option<DateTime<Utc>>,
+}
+
+/// Checks if two players overlap, given their X and Y coordinates, with [tolerance] extra
+/// units allowed between them on the X axis. Bounds are inclusive, so players standing right
+/// next to each other touch, as collisions leave them
+pub fn touching(a: (f64, f64), b: (f64, f64), tolerance: f64) -> bool {
+    (a.0 - b.0).abs() <= PLAYER_WIDTH + tolerance && (a.1 - b.1).abs() <= PLAYER_HEIGHT
+}
+
+impl State {
+    /// Keeps the player's current position, to rewind it when checking tags
+    pub(crate) fn record_sample(&mut self, player_id: usize, at: DateTime<Utc>) {
+        let Some(player) = self.players.get(&player_id) else {
+            return;
+        };
+        let sample = PlayerSample {
+            at,
+            x: player.x,
+            last_jump_at: player.last_jump_at,
+        };
+        let history = self.history.entry(player_id).or_default();
+        // Samples applied at the same time replace each other
+        if history.back().is_some_and(|last| last.at >= at) {
+            history.pop_back();
+        }
+        history.push_back(sample);
+    }
+
+    /// Drops the samples no tag can rewind to anymore, keeping the last one before the oldest
+    /// time a tag can rewind to
+    fn prune_history(&mut self, at: DateTime<Utc>) {
+        let oldest = at - MAX_REWIND;
+        for history in self.history.values_mut() {
+            while history.get(1).is_some_and(|next| next.at <= oldest) {
+                history.pop_front();
+            }
+        }
+    }
+
+    /// Position of a player at the given time, as X and Y coordinates. Players are at their
+    /// first known position before it was recorded
+    fn position_at(&self, player: &Player, at: DateTime<Utc>) -> (f64, f64) {
+        let sample = self.history.get(&player.id).and_then(|history| {
+            history
+                .iter()
+                .rev()
+                .find(|sample| sample.at <= at)
+                .or(history.front())
+        });
+        match sample {
+            Some(sample) => {
+                let rewound = Player {
+                    last_jump_at: sample.last_jump_at,
+                    ..player.clone()
+                };
+                (sample.x, rewound.y_at(at))
+            }
+            None => (player.x, player.y_at(at)),
+        }
+    }
+
+    /// Makes the player that is it tag the target, as the tagger saw the target at [seen_at].
+    /// The target is rewound to that time, up to [MAX_REWIND] before [at], and must touch the
+    /// tagger's position at [at].
+    pub fn player_tag(
+        &mut self,
+        player_id: usize,
+        target: usize,
+        seen_at: DateTime<Utc>,
+        at: DateTime<Utc>,
+    ) -> Result<(), StateError> {
+        if self.it != Some(player_id) {
+            return Err(StateError::NotIt(player_id));
+        }
+        if target == player_id {
+            return Err(StateError::TagSelf(player_id));
+        }
+        if self.it_since.is_some_and(|since| at - since < TAG_COOLDOWN) {
+            return Err(StateError::TagCooldown {
+                seconds: TAG_COOLDOWN.as_seconds_f64(),
+            });
+        }
+        let tagger = self
+            .players
+            .get(&player_id)
+            .ok_or(StateError::UnknownPlayer(player_id))?;
+        let tagged = self
+            .players
+            .get(&target)
+            .ok_or(StateError::UnknownPlayer(target))?;
+
+        let seen_at = seen_at.clamp(at - MAX_REWIND, at);
+        let tagger_position = (tagger.x, tagger.y_at(at));
+        let target_position = self.position_at(tagged, seen_at);
+        if !touching(tagger_position, target_position, TAG_TOLERANCE) {
+            return Err(StateError::TagMissed {
+                target,
+                distance: (tagger_position.0 - target_position.0).abs(),
+            });
+        }
+        self.pass_tag(target, at);
+        Ok(())
+    }
+
+    /// Makes the given player it
+    fn pass_tag(&mut self, player_id: usize, at: DateTime<Utc>) {
+        self.it = Some(player_id);
+        self.it_since = Some(at);
+    }
+
+    /// Scores the time since the last tick for every player but the one that is it, then picks
+    /// a new player to be it if needed. AI players that are it tag the players they touch,
+    /// without lag to compensate.
+    pub(crate) fn tag_tick(&mut self, at: DateTime<Utc>) {
+        self.prune_history(at);
+        if self.config.mode != GameMode::Tag {
+            return;
+        }
+        // Nobody is it without another player to chase
+        if self.players.len() < 2 {
+            self.it = None;
+            self.it_since = None;
+            return;
+        }
+
+        let Some(it) = self.it.filter(|it| self.players.contains_key(it)) else {
+            // The leading player is it first, the player with the lowest id on ties
+            let leader = self
+                .players
+                .values()
+                .max_by(|a, b| a.score.total_cmp(&b.score).then(b.id.cmp(&a.id)))
+                .map(|player| player.id);
+            if let Some(leader) = leader {
+                self.pass_tag(leader, at);
+            }
+            return;
+        };
+
+        let seconds = (at - self.timestamp).as_seconds_f64().max(0.0);
+        for player in self.players.values_mut() {
+            if player.id != it {
+                player.score += seconds;
+            }
+        }
+
+        let cooled_down = self.it_since.is_none_or(|since| at - since >= TAG_COOLDOWN);
+        if self.is_ai(it) && cooled_down {
+            let tagger = &self.players[&it];
+            let tagger_position = (tagger.x, tagger.y_at(at));
+            let mut ids = self.players.keys().copied().collect::<Vec<_>>();
+            ids.sort_unstable();
+            let tagged = ids.into_iter().find(|id| {
+                let player = &self.players[id];
+                *id != it && touching(tagger_position, (player.x, player.y_at(at)), 0.0)
+            });
+            if let Some(tagged) = tagged {
+                self.pass_tag(tagged, at);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::PlayerAction,
+        state::{fixture, WorldConfig},
+    };
+
+    #[test]
+    fn test_tag_rewinds_target() {
+        let mut state = fixture::two_players(WorldConfig {
+            mode: GameMode::Tag,
+            ..Default::default()
+        });
+        let start = state.timestamp;
+        let at = |ms| fixture::after(start, ms);
+        state.tick_at(at(0));
+        assert_eq!(state.it, Some(0));
+        assert!(matches!(
+            state.player_tag(1, 0, at(1000), at(1000)),
+            Err(StateError::NotIt(1))
+        ));
+        assert!(matches!(
+            state.player_tag(0, 0, at(1000), at(1000)),
+            Err(StateError::TagSelf(0))
+        ));
+
+        // Bob runs away after Alice's client last saw him next to her
+        let run = PlayerAction::Move {
+            delta_x: 3.0,
+            id: uuid::Uuid::new_v4(),
+        };
+        state.player_action(1, &run, at(1100)).unwrap();
+        state.tick_at(at(1200));
+        assert!(matches!(
+            state.player_tag(0, 1, at(1150), at(1300)),
+            Err(StateError::TagMissed { target: 1, .. })
+        ));
+        state.player_tag(0, 1, at(1000), at(1300)).unwrap();
+        assert_eq!(state.it, Some(1));
+        // Bob scored while Alice was it
+        assert_eq!(state.players[&0].score, 0.0);
+        assert!(state.players[&1].score > 1.1);
+    }
+}
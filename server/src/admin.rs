@@ -0,0 +1,422 @@
+//! Admin HTTP API to inspect and manage the running server, protected by the admin token.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use netcode::{
+    event::{DisconnectNotice, RoomId},
+    DISCONNECT_NOTICE_CHANNEL,
+};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+use crate::{logging::LogFilterError, room::RoomError, AppState};
+
+/// Represents all possible errors that can occur when handling an admin request
+#[derive(thiserror::Error, Debug)]
+pub enum AdminError {
+    #[error("[ERROR - UNAUTHORIZED] Missing or invalid admin token.")]
+    Unauthorized,
+    #[error(transparent)]
+    Room(#[from] RoomError),
+    #[error("[ERROR - UNKNOWN PLAYER] No player with id {player_id} in room {room_id}.")]
+    UnknownPlayer { room_id: RoomId, player_id: usize },
+    #[error("[ERROR - ADMIN] {0} must be greater than 0")]
+    Zero(&'static str),
+    #[error(transparent)]
+    LogFilter(#[from] LogFilterError),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::Room(_) | AdminError::UnknownPlayer { .. } => StatusCode::NOT_FOUND,
+            AdminError::Zero(_) | AdminError::LogFilter(_) => StatusCode::BAD_REQUEST,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// A connected socket, and its player if it has joined its room's game
+#[derive(Debug, Serialize)]
+struct ConnectedPlayer {
+    socket_id: String,
+    room_id: RoomId,
+    player_id: Option<usize>,
+    name: Option<String>,
+    spectating: bool,
+    x: Option<f64>,
+    y: Option<f64>,
+    rtt_ms: Option<u128>,
+}
+
+/// Tick and broadcast intervals of the rooms. Unset fields are left unchanged when updating
+#[derive(Debug, Serialize, Deserialize)]
+struct Intervals {
+    tick_interval_ms: Option<u64>,
+    broadcast_interval_ms: Option<u64>,
+}
+
+/// Routes of the admin API, under `/admin`. Every request must carry the admin token as a
+/// bearer token.
+pub fn router(app: Arc<AppState>, token: String) -> Router {
+    Router::new()
+        .route("/players", get(list_players))
+        .route("/rooms/{room_id}/state", get(dump_state))
+        .route("/rooms/{room_id}/players/{player_id}/kick", post(kick))
+        .route("/rooms/{room_id}/reset", post(reset))
+        .route("/intervals", get(get_intervals).put(set_intervals))
+        .route("/log-filter", get(get_log_filter).put(set_log_filter))
+        .layer(middleware::from_fn_with_state(Arc::new(token), authorize))
+        .with_state(app)
+}
+
+type AdminState = State<Arc<AppState>>;
+
+/// Rejects requests that do not carry the admin token
+async fn authorize(
+    State(token): State<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        // Compared in constant time, so the time taken does not leak how much of it matched
+        Some(bearer) if bool::from(bearer.as_bytes().ct_eq(token.as_bytes())) => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(AdminError::Unauthorized),
+    }
+}
+
+/// Lists all connected sockets with their player's position and round trip time
+async fn list_players(State(app): AdminState) -> Json<Vec<ConnectedPlayer>> {
+    let mut players = app
+        .sessions
+        .all()
+        .iter()
+        .map(|session| {
+            let session = session.lock().unwrap();
+            let state = session.room.lock_state();
+            let player = session
+                .player_id
+                .and_then(|player_id| state.players.get(&player_id));
+            ConnectedPlayer {
+                socket_id: session.socket.id.to_string(),
+                room_id: session.room.id.clone(),
+                player_id: session.player_id,
+                name: player.map(|player| player.name.clone()),
+                spectating: session.spectating,
+                x: player.map(|player| player.x),
+                y: player.map(|player| player.y()),
+                rtt_ms: session.rtt.map(|rtt| rtt.as_millis()),
+            }
+        })
+        .collect::<Vec<_>>();
+    players.sort_by(|a, b| (&a.room_id, a.player_id).cmp(&(&b.room_id, b.player_id)));
+    Json(players)
+}
+
+/// Dumps the current game state of a room
+async fn dump_state(
+    State(app): AdminState,
+    Path(room_id): Path<RoomId>,
+) -> Result<Json<netcode::State>, AdminError> {
+    let room = app
+        .rooms
+        .get(&room_id)
+        .ok_or(RoomError::UnknownRoom(room_id))?;
+    let state = room.lock_state().clone();
+    Ok(Json(state))
+}
+
+/// Disconnects the socket controlling a player, telling the client it was kicked
+async fn kick(
+    State(app): AdminState,
+    Path((room_id, player_id)): Path<(RoomId, usize)>,
+) -> Result<StatusCode, AdminError> {
+    let socket = app.sessions.all().iter().find_map(|session| {
+        let session = session.lock().unwrap();
+        (session.room.id == room_id && session.player_id == Some(player_id))
+            .then(|| session.socket.clone())
+    });
+    let Some(socket) = socket else {
+        return Err(AdminError::UnknownPlayer { room_id, player_id });
+    };
+
+    info!(room = %room_id, player_id, "kicking player");
+    let notice = serde_json::to_string(&DisconnectNotice::Kicked).unwrap();
+    let _ = socket.emit(DISCONNECT_NOTICE_CHANNEL, &notice);
+    let _ = socket.disconnect();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Resets a room's game, removing all of its players. The clients in the room start over as
+/// if they had just entered it.
+async fn reset(
+    State(app): AdminState,
+    Path(room_id): Path<RoomId>,
+) -> Result<StatusCode, AdminError> {
+    let room = app
+        .rooms
+        .get(&room_id)
+        .ok_or(RoomError::UnknownRoom(room_id.clone()))?;
+    room.reset();
+    info!(room = %room_id, "room reset");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Gets the current tick and broadcast intervals
+async fn get_intervals(State(app): AdminState) -> Json<Intervals> {
+    Json(Intervals {
+        tick_interval_ms: Some(app.rooms.intervals.tick().as_millis() as u64),
+        broadcast_interval_ms: Some(app.rooms.intervals.broadcast().as_millis() as u64),
+    })
+}
+
+/// Changes the tick and/or broadcast interval of every room
+async fn set_intervals(
+    State(app): AdminState,
+    Json(intervals): Json<Intervals>,
+) -> Result<Json<Intervals>, AdminError> {
+    if intervals.tick_interval_ms == Some(0) {
+        return Err(AdminError::Zero("tick_interval_ms"));
+    }
+    if intervals.broadcast_interval_ms == Some(0) {
+        return Err(AdminError::Zero("broadcast_interval_ms"));
+    }
+
+    if let Some(tick_interval_ms) = intervals.tick_interval_ms {
+        info!(tick_interval_ms, "tick interval changed");
+        app.rooms
+            .intervals
+            .set_tick(Duration::from_millis(tick_interval_ms));
+    }
+    if let Some(broadcast_interval_ms) = intervals.broadcast_interval_ms {
+        info!(broadcast_interval_ms, "broadcast interval changed");
+        app.rooms
+            .intervals
+            .set_broadcast(Duration::from_millis(broadcast_interval_ms));
+    }
+    Ok(get_intervals(State(app)).await)
+}
+
+/// Gets the current log filter directives
+async fn get_log_filter(State(app): AdminState) -> String {
+    app.log_filter.get()
+}
+
+/// Replaces the log filter directives with the plain text body, such as `info,server=debug`
+async fn set_log_filter(State(app): AdminState, directives: String) -> Result<String, AdminError> {
+    app.log_filter.set(directives.trim())?;
+    info!(filter = %app.log_filter.get(), "log filter changed");
+    Ok(app.log_filter.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use netcode::{state::fixture::join_request, DEFAULT_ROOM};
+    use socketioxide::SocketIo;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{config::Config, session::SharedSession};
+
+    /// Sends a request carrying the admin token, returning the response's status and body
+    async fn send(router: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Waits until the server has the given number of connected sockets
+    async fn wait_for_sessions(app: &AppState, count: usize) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while app.sessions.len() != count {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("sockets did not connect or disconnect");
+    }
+
+    /// Connects sockets through the server's socket handlers, returning their sessions. The
+    /// sockets stay connected as long as the returned [SocketIo] is kept.
+    async fn connect(app: &Arc<AppState>, sockets: usize) -> (SocketIo, Vec<SharedSession>) {
+        let (_, io) = SocketIo::builder().with_state(app.clone()).build_svc();
+        io.ns("/", crate::on_connect);
+        for _ in 0..sockets {
+            // The packets sent to the socket are not looked at
+            let _ = io.new_dummy_sock("/", ()).await;
+        }
+        wait_for_sessions(app, sockets).await;
+        (io, app.sessions.all())
+    }
+
+    /// Makes the socket's player join its room's game under the given name
+    fn join(session: &SharedSession, name: &str) -> usize {
+        let mut guard = session.lock().unwrap();
+        let room = guard.room.clone();
+        let socket = guard.socket.clone();
+        room.join(&socket, &mut guard, session, join_request(name))
+            .unwrap()
+            .player_id
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_admin_token() {
+        let router = router(AppState::test(Config::default()), "secret".to_string());
+        let cases = [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("Bearer wrong"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer secre"), StatusCode::UNAUTHORIZED),
+            (Some("secret"), StatusCode::UNAUTHORIZED),
+            (Some("Bearer secret"), StatusCode::OK),
+        ];
+        for (authorization, status) in cases {
+            let mut request = Request::get("/intervals");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let response = router
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{authorization:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dump_and_reset_room() {
+        let app = AppState::test(Config::default());
+        let router = router(app.clone(), "secret".to_string());
+        app.rooms
+            .lobby()
+            .lock_state()
+            .player_join(&join_request("Alice"))
+            .unwrap();
+
+        let (status, body) = send(&router, "GET", "/rooms/lobby/state", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let state: netcode::State = serde_json::from_str(&body).unwrap();
+        assert_eq!(state.players[&0].name, "Alice");
+        for (method, uri) in [
+            ("GET", "/rooms/nowhere/state"),
+            ("POST", "/rooms/nowhere/reset"),
+        ] {
+            let (status, body) = send(&router, method, uri, "").await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+            assert_eq!(
+                body,
+                RoomError::UnknownRoom("nowhere".to_string()).to_string()
+            );
+        }
+
+        let (status, _) = send(&router, "POST", "/rooms/lobby/reset", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(app.rooms.lobby().lock_state().players.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_and_kick_players() {
+        let app = AppState::test(Config::default());
+        let router = router(app.clone(), "secret".to_string());
+        let (_io, sessions) = connect(&app, 2).await;
+        let alice = join(&sessions[0], "Alice");
+
+        let (status, body) = send(&router, "GET", "/players", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let players: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(players.len(), 2);
+        // Sockets without a player come first
+        assert_eq!(players[0]["player_id"], serde_json::Value::Null);
+        assert_eq!(players[1]["player_id"], alice);
+        assert_eq!(players[1]["name"], "Alice");
+        assert_eq!(players[1]["room_id"], DEFAULT_ROOM);
+
+        let (status, body) = send(&router, "POST", "/rooms/lobby/players/9/kick", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let unknown = AdminError::UnknownPlayer {
+            room_id: DEFAULT_ROOM.to_string(),
+            player_id: 9,
+        };
+        assert_eq!(body, unknown.to_string());
+
+        let uri = format!("/rooms/lobby/players/{alice}/kick");
+        let (status, _) = send(&router, "POST", &uri, "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        wait_for_sessions(&app, 1).await;
+        assert!(app.rooms.lobby().lock_state().players.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_change_intervals() {
+        let router = router(AppState::test(Config::default()), "secret".to_string());
+        let (status, body) = send(&router, "GET", "/intervals", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"{"tick_interval_ms":333,"broadcast_interval_ms":333}"#
+        );
+
+        let (status, body) = send(&router, "PUT", "/intervals", r#"{"tick_interval_ms":0}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, AdminError::Zero("tick_interval_ms").to_string());
+
+        let (status, body) = send(
+            &router,
+            "PUT",
+            "/intervals",
+            r#"{"broadcast_interval_ms":50}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"{"tick_interval_ms":333,"broadcast_interval_ms":50}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_log_filter() {
+        let router = router(AppState::test(Config::default()), "secret".to_string());
+        let (status, body) = send(&router, "PUT", "/log-filter", "warn,server=debug\n").await;
+        assert_eq!(status, StatusCode::OK);
+        // Directives are listed from the most specific
+        assert_eq!(body, "server=debug,warn");
+        let (status, body) = send(&router, "GET", "/log-filter", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "server=debug,warn");
+
+        let (status, _) = send(&router, "PUT", "/log-filter", "server=loudest").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, body) = send(&router, "GET", "/log-filter", "").await;
+        assert_eq!(body, "server=debug,warn");
+    }
+}
@@ -0,0 +1,506 @@
+//! Server configuration read from a config file, with CLI flag and environment overrides.
+
+use std::{path::PathBuf, time::Duration};
+
+use clap::Parser;
+use netcode::{ai::AiBehaviour, state::WorldConfig, tag::GameMode};
+use serde::{Deserialize, Serialize};
+
+/// Command line flags of the server. Every flag can also be set through its environment variable.
+#[derive(Debug, Parser)]
+#[command(about = "NetCube game server")]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(short, long, env = "NETCUBE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to bind the server to
+    #[arg(long, env = "NETCUBE_HOST")]
+    pub host: Option<String>,
+
+    /// Port to bind the server to
+    #[arg(short, long, env = "NETCUBE_PORT")]
+    pub port: Option<u16>,
+
+    /// Milliseconds between each simulation tick
+    #[arg(long, env = "NETCUBE_TICK_INTERVAL_MS")]
+    pub tick_interval_ms: Option<u64>,
+
+    /// Milliseconds between each state broadcast to the clients
+    #[arg(long, env = "NETCUBE_BROADCAST_INTERVAL_MS")]
+    pub broadcast_interval_ms: Option<u64>,
+
+    /// Max number of players in the world at once
+    #[arg(long, env = "NETCUBE_MAX_PLAYERS")]
+    pub max_players: Option<usize>,
+
+    /// Units traveled per second by a moving player. Clients and AI players move at this
+    /// speed, the server does not check the moves of human players against it
+    #[arg(long, env = "NETCUBE_MAX_UNITS_PER_SECOND")]
+    pub max_units_per_second: Option<f64>,
+
+    /// Objective of the game in every room, `free` or `tag`
+    #[arg(long, env = "NETCUBE_GAME_MODE")]
+    pub game_mode: Option<GameMode>,
+
+    /// Make players collide and push each other
+    #[arg(long, env = "NETCUBE_COLLISION")]
+    pub collision: Option<bool>,
+
+    /// Queue joins to full rooms, admitting them in order as slots free up
+    #[arg(long, env = "NETCUBE_JOIN_QUEUE")]
+    pub join_queue: Option<bool>,
+
+    /// Max number of rooms open at once, the lobby included
+    #[arg(long, env = "NETCUBE_MAX_ROOMS")]
+    pub max_rooms: Option<usize>,
+
+    /// Token required by the admin API. The admin API is disabled if not set
+    #[arg(long, env = "NETCUBE_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Log filter directives, such as `info,server=debug`
+    #[arg(long, env = "NETCUBE_LOG")]
+    pub log_filter: Option<String>,
+
+    /// File to write the state of every room to when the server shuts down
+    #[arg(long, env = "NETCUBE_FINAL_SNAPSHOT")]
+    pub final_snapshot: Option<PathBuf>,
+
+    /// Max number of actions a client can send per second
+    #[arg(long, env = "NETCUBE_RATE_LIMIT_MESSAGES_PER_SECOND")]
+    pub rate_limit_messages_per_second: Option<f64>,
+
+    /// Max number of action bytes a client can send per second
+    #[arg(long, env = "NETCUBE_RATE_LIMIT_BYTES_PER_SECOND")]
+    pub rate_limit_bytes_per_second: Option<f64>,
+
+    /// Seconds worth of actions a client can send at once, above the per second limits
+    #[arg(long, env = "NETCUBE_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<f64>,
+
+    /// Number of actions dropped within a second after which a client is disconnected
+    #[arg(long, env = "NETCUBE_RATE_LIMIT_MAX_DROPPED")]
+    pub rate_limit_max_dropped: Option<u32>,
+
+    /// Max number of chat messages a player can send per second
+    #[arg(long, env = "NETCUBE_CHAT_MESSAGES_PER_SECOND")]
+    pub chat_messages_per_second: Option<f64>,
+
+    /// Number of chat messages a player can send at once, above the per second limit
+    #[arg(long, env = "NETCUBE_CHAT_BURST")]
+    pub chat_burst: Option<f64>,
+
+    /// Distance in units around a client's player within which other players are sent to it
+    #[arg(long, env = "NETCUBE_INTEREST_RADIUS")]
+    pub interest_radius: Option<f64>,
+
+    /// Directory to record a replay file of every room's game to
+    #[arg(long, env = "NETCUBE_REPLAY_DIR")]
+    pub replay_dir: Option<PathBuf>,
+
+    /// Behaviour of each AI player added to every room, such as `patrol,follow,jump`
+    #[arg(long, env = "NETCUBE_AI_PLAYERS", value_delimiter = ',')]
+    pub ai_players: Option<Vec<AiBehaviour>>,
+
+    /// File the world is saved to periodically and on shutdown, and restored from at startup
+    #[arg(long, env = "NETCUBE_WORLD_SAVE")]
+    pub world_save: Option<PathBuf>,
+
+    /// Seconds between each save of the world
+    #[arg(long, env = "NETCUBE_SAVE_INTERVAL_SECS")]
+    pub save_interval_secs: Option<u64>,
+}
+
+/// Effective configuration of the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Milliseconds between each simulation tick
+    pub tick_interval_ms: u64,
+    /// Milliseconds between each state broadcast to the clients.
+    /// It is recommended to keep this value above 15ms.
+    pub broadcast_interval_ms: u64,
+    /// Max number of players in the world at once. Unlimited if not set
+    pub max_players: Option<usize>,
+    /// Units traveled per second by a moving player. Clients and AI players move at this
+    /// speed, the server does not check the moves of human players against it
+    pub max_units_per_second: f64,
+    /// Objective of the game in every room
+    pub game_mode: GameMode,
+    /// Make players collide and push each other
+    pub collision: bool,
+    /// Queue joins to full rooms, admitting them in order as slots free up
+    pub join_queue: bool,
+    /// Max number of rooms open at once, the lobby included. Clients cannot create rooms
+    /// past it
+    pub max_rooms: usize,
+    /// Token required by the admin API. The admin API is disabled if not set.
+    /// Never printed with the effective config
+    #[serde(skip_serializing)]
+    pub admin_token: Option<String>,
+    /// Log filter directives, such as `info,server=debug`. Can be changed at runtime through
+    /// the admin API
+    pub log_filter: String,
+    /// File to write the state of every room to when the server shuts down. Not written if not
+    /// set
+    pub final_snapshot: Option<PathBuf>,
+    /// Max number of actions a client can send per second
+    pub rate_limit_messages_per_second: f64,
+    /// Max number of action bytes a client can send per second
+    pub rate_limit_bytes_per_second: f64,
+    /// Seconds worth of actions a client can send at once, above the per second limits
+    pub rate_limit_burst: f64,
+    /// Number of actions dropped within a second after which a client is disconnected
+    pub rate_limit_max_dropped: u32,
+    /// Max number of chat messages a player can send per second
+    pub chat_messages_per_second: f64,
+    /// Number of chat messages a player can send at once, above the per second limit
+    pub chat_burst: f64,
+    /// Distance in units around a client's player within which other players are sent to it.
+    /// Every client receives every player if not set
+    pub interest_radius: Option<f64>,
+    /// Directory to record a replay file of every room's game to. Games are not recorded if not
+    /// set
+    pub replay_dir: Option<PathBuf>,
+    /// Behaviour of each AI player added to every room. Humans joining a full room take the
+    /// place of an AI player
+    pub ai_players: Vec<AiBehaviour>,
+    /// File the world is saved to periodically and on shutdown, and restored from at startup.
+    /// The world is not saved if not set
+    pub world_save: Option<PathBuf>,
+    /// Seconds between each save of the world
+    pub save_interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let world = WorldConfig::default();
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 7878,
+            tick_interval_ms: 333,
+            broadcast_interval_ms: 333,
+            max_players: world.max_players,
+            max_units_per_second: world.max_units_per_second,
+            game_mode: world.mode,
+            collision: world.collision,
+            join_queue: false,
+            max_rooms: 32,
+            admin_token: None,
+            log_filter: "info".to_string(),
+            final_snapshot: None,
+            rate_limit_messages_per_second: 60.0,
+            rate_limit_bytes_per_second: 8192.0,
+            rate_limit_burst: 2.0,
+            rate_limit_max_dropped: 60,
+            chat_messages_per_second: 1.0,
+            chat_burst: 5.0,
+            interest_radius: None,
+            replay_dir: None,
+            ai_players: Vec::new(),
+            world_save: None,
+            save_interval_secs: 30,
+        }
+    }
+}
+
+/// Represents all possible errors that can occur when loading the config
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("[ERROR - CONFIG] Could not read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("[ERROR - CONFIG] Could not parse config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("[ERROR - CONFIG] {0} must be greater than 0")]
+    Zero(&'static str),
+    #[error("[ERROR - CONFIG] {0} must be at least 1")]
+    BurstTooSmall(&'static str),
+    #[error("[ERROR - CONFIG] {0} must be a finite number")]
+    NotFinite(&'static str),
+}
+
+impl Config {
+    /// Loads the config file given on the command line, if any, and applies the CLI and
+    /// environment overrides on top of it.
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Read(path.clone(), err))?;
+                toml::from_str(&content).map_err(|err| ConfigError::Parse(path.clone(), err))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(host) = cli.host {
+            config.host = host;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(tick_interval_ms) = cli.tick_interval_ms {
+            config.tick_interval_ms = tick_interval_ms;
+        }
+        if let Some(broadcast_interval_ms) = cli.broadcast_interval_ms {
+            config.broadcast_interval_ms = broadcast_interval_ms;
+        }
+        if let Some(max_players) = cli.max_players {
+            config.max_players = Some(max_players);
+        }
+        if let Some(max_units_per_second) = cli.max_units_per_second {
+            config.max_units_per_second = max_units_per_second;
+        }
+        if let Some(game_mode) = cli.game_mode {
+            config.game_mode = game_mode;
+        }
+        if let Some(collision) = cli.collision {
+            config.collision = collision;
+        }
+        if let Some(join_queue) = cli.join_queue {
+            config.join_queue = join_queue;
+        }
+        if let Some(max_rooms) = cli.max_rooms {
+            config.max_rooms = max_rooms;
+        }
+        if let Some(admin_token) = cli.admin_token {
+            config.admin_token = Some(admin_token);
+        }
+        if let Some(log_filter) = cli.log_filter {
+            config.log_filter = log_filter;
+        }
+        if let Some(final_snapshot) = cli.final_snapshot {
+            config.final_snapshot = Some(final_snapshot);
+        }
+        if let Some(rate_limit_messages_per_second) = cli.rate_limit_messages_per_second {
+            config.rate_limit_messages_per_second = rate_limit_messages_per_second;
+        }
+        if let Some(rate_limit_bytes_per_second) = cli.rate_limit_bytes_per_second {
+            config.rate_limit_bytes_per_second = rate_limit_bytes_per_second;
+        }
+        if let Some(rate_limit_burst) = cli.rate_limit_burst {
+            config.rate_limit_burst = rate_limit_burst;
+        }
+        if let Some(rate_limit_max_dropped) = cli.rate_limit_max_dropped {
+            config.rate_limit_max_dropped = rate_limit_max_dropped;
+        }
+        if let Some(chat_messages_per_second) = cli.chat_messages_per_second {
+            config.chat_messages_per_second = chat_messages_per_second;
+        }
+        if let Some(chat_burst) = cli.chat_burst {
+            config.chat_burst = chat_burst;
+        }
+        if let Some(interest_radius) = cli.interest_radius {
+            config.interest_radius = Some(interest_radius);
+        }
+        if let Some(replay_dir) = cli.replay_dir {
+            config.replay_dir = Some(replay_dir);
+        }
+        if let Some(ai_players) = cli.ai_players {
+            config.ai_players = ai_players;
+        }
+        if let Some(world_save) = cli.world_save {
+            config.world_save = Some(world_save);
+        }
+        if let Some(save_interval_secs) = cli.save_interval_secs {
+            config.save_interval_secs = save_interval_secs;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the config values can be used by the server
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.tick_interval_ms == 0 {
+            return Err(ConfigError::Zero("tick_interval_ms"));
+        }
+        if self.broadcast_interval_ms == 0 {
+            return Err(ConfigError::Zero("broadcast_interval_ms"));
+        }
+        if self.max_players == Some(0) {
+            return Err(ConfigError::Zero("max_players"));
+        }
+        if self.max_rooms == 0 {
+            return Err(ConfigError::Zero("max_rooms"));
+        }
+        if !self.max_units_per_second.is_finite() {
+            return Err(ConfigError::NotFinite("max_units_per_second"));
+        }
+        if self.max_units_per_second <= 0.0 {
+            return Err(ConfigError::Zero("max_units_per_second"));
+        }
+        if self.rate_limit_messages_per_second <= 0.0 {
+            return Err(ConfigError::Zero("rate_limit_messages_per_second"));
+        }
+        if self.rate_limit_bytes_per_second <= 0.0 {
+            return Err(ConfigError::Zero("rate_limit_bytes_per_second"));
+        }
+        if self.rate_limit_burst < 1.0 {
+            return Err(ConfigError::BurstTooSmall("rate_limit_burst"));
+        }
+        if self.chat_messages_per_second <= 0.0 {
+            return Err(ConfigError::Zero("chat_messages_per_second"));
+        }
+        if self.chat_burst < 1.0 {
+            return Err(ConfigError::BurstTooSmall("chat_burst"));
+        }
+        if self.interest_radius.is_some_and(|radius| radius <= 0.0) {
+            return Err(ConfigError::Zero("interest_radius"));
+        }
+        if self.save_interval_secs == 0 {
+            return Err(ConfigError::Zero("save_interval_secs"));
+        }
+        Ok(())
+    }
+
+    /// Time between each tick update on the server's state
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_millis(self.tick_interval_ms)
+    }
+
+    /// Time between each state broadcast to the clients
+    pub fn broadcast_interval(&self) -> Duration {
+        Duration::from_millis(self.broadcast_interval_ms)
+    }
+
+    /// Time between each save of the world
+    pub fn save_interval(&self) -> Duration {
+        Duration::from_secs(self.save_interval_secs)
+    }
+
+    /// Rules of the game world
+    pub fn world(&self) -> WorldConfig {
+        WorldConfig {
+            max_players: self.max_players,
+            max_units_per_second: self.max_units_per_second,
+            mode: self.game_mode,
+            collision: self.collision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_overrides_env_overrides_file() {
+        let path = std::env::temp_dir().join(format!("netcube-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "port = 1000\ntick_interval_ms = 10\nbroadcast_interval_ms = 20\n",
+        )
+        .unwrap();
+        // The only test setting environment variables, as they are shared by every test
+        std::env::set_var("NETCUBE_PORT", "2000");
+        std::env::set_var("NETCUBE_TICK_INTERVAL_MS", "30");
+        let cli = Cli::try_parse_from([
+            "server",
+            "--config",
+            path.to_str().unwrap(),
+            "--port",
+            "3000",
+        ]);
+        std::env::remove_var("NETCUBE_PORT");
+        std::env::remove_var("NETCUBE_TICK_INTERVAL_MS");
+        let config = Config::load(cli.unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.port, 3000);
+        assert_eq!(config.tick_interval_ms, 30);
+        assert_eq!(config.broadcast_interval_ms, 20);
+        assert_eq!(config.host, Config::default().host);
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_values() {
+        assert!(Config::default().validate().is_ok());
+        let invalid = [
+            (
+                Config {
+                    tick_interval_ms: 0,
+                    ..Default::default()
+                },
+                ConfigError::Zero("tick_interval_ms"),
+            ),
+            (
+                Config {
+                    max_players: Some(0),
+                    ..Default::default()
+                },
+                ConfigError::Zero("max_players"),
+            ),
+            (
+                Config {
+                    max_rooms: 0,
+                    ..Default::default()
+                },
+                ConfigError::Zero("max_rooms"),
+            ),
+            (
+                Config {
+                    max_units_per_second: 0.0,
+                    ..Default::default()
+                },
+                ConfigError::Zero("max_units_per_second"),
+            ),
+            (
+                Config {
+                    max_units_per_second: f64::INFINITY,
+                    ..Default::default()
+                },
+                ConfigError::NotFinite("max_units_per_second"),
+            ),
+            (
+                Config {
+                    max_units_per_second: f64::NAN,
+                    ..Default::default()
+                },
+                ConfigError::NotFinite("max_units_per_second"),
+            ),
+            (
+                Config {
+                    rate_limit_bytes_per_second: -1.0,
+                    ..Default::default()
+                },
+                ConfigError::Zero("rate_limit_bytes_per_second"),
+            ),
+            (
+                Config {
+                    rate_limit_burst: 0.0,
+                    ..Default::default()
+                },
+                ConfigError::BurstTooSmall("rate_limit_burst"),
+            ),
+            (
+                Config {
+                    chat_burst: 0.5,
+                    ..Default::default()
+                },
+                ConfigError::BurstTooSmall("chat_burst"),
+            ),
+            (
+                Config {
+                    interest_radius: Some(0.0),
+                    ..Default::default()
+                },
+                ConfigError::Zero("interest_radius"),
+            ),
+            (
+                Config {
+                    save_interval_secs: 0,
+                    ..Default::default()
+                },
+                ConfigError::Zero("save_interval_secs"),
+            ),
+        ];
+        for (config, expected) in invalid {
+            let err = config.validate().unwrap_err();
+            assert_eq!(err.to_string(), expected.to_string());
+        }
+    }
+}
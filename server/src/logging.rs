@@ -0,0 +1,58 @@
+//! Leveled, structured logging through `tracing`, with a filter that can be changed at runtime.
+
+use tracing_subscriber::{
+    filter::ParseError, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter,
+    Registry,
+};
+
+/// Represents all possible errors that can occur when setting the log filter
+#[derive(thiserror::Error, Debug)]
+pub enum LogFilterError {
+    #[error("[ERROR - LOG FILTER] Invalid log filter: {0}")]
+    Parse(#[from] ParseError),
+    #[error("[ERROR - LOG FILTER] Could not apply the log filter: {0}")]
+    Reload(#[from] reload::Error),
+}
+
+/// Handle to change the log filter of the running server
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    /// Current filter directives, such as `info,server=debug`
+    pub fn get(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the filter directives, effective immediately
+    pub fn set(&self, directives: &str) -> Result<(), LogFilterError> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.handle.reload(filter)?;
+        Ok(())
+    }
+}
+
+/// Installs the global logger with the given filter directives
+pub fn init(directives: &str) -> Result<LogFilter, LogFilterError> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(directives)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .init();
+    Ok(LogFilter { handle })
+}
+
+#[cfg(test)]
+impl LogFilter {
+    /// Filter that is not installed as the global logger, for tests. Its layer is leaked, so
+    /// the filter can still be read and changed
+    pub fn detached() -> Self {
+        let (layer, handle) = reload::Layer::new(EnvFilter::default());
+        Box::leak(Box::new(layer));
+        Self { handle }
+    }
+}
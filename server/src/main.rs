@@ -1,37 +1,99 @@
 //! Main entrypoint for the server-side SocketIO API.
 
-use netcode::{event::JoinResponse, ACTION_CHANNEL, ERROR_CHANNEL, JOIN_CHANNEL, STATE_CHANNEL};
+use chrono::Utc;
+use clap::Parser;
+use config::{Cli, Config};
+use logging::LogFilter;
+use metrics::METRICS;
+use netcode::{
+    event::{ChatError, ChatMessage, DisconnectNotice, RoomResponse},
+    Action, ACTION_CHANNEL, CHAT_CHANNEL, DISCONNECT_NOTICE_CHANNEL, ERROR_CHANNEL, JOIN_CHANNEL,
+    JOIN_REJECTED_CHANNEL, PING_CHANNEL, ROOM_CHANNEL,
+};
+use rate_limit::{RateLimitError, RateLimiter, ReceivedSize, TokenBucket};
+use room::{Room, RoomError, Rooms};
+use session::{Session, SessionError, Sessions, SharedSession};
 use socketioxide::{
     extract::{Data, SocketRef, State},
     socket::DisconnectReason,
     SocketIo,
 };
 use std::{
+    collections::HashSet,
     sync::{Arc, Mutex},
     time::Duration,
 };
+use tokio::sync::watch;
+use tracing::{debug, info, trace, warn};
+
+mod admin;
+mod config;
+mod logging;
+mod metrics;
+mod persistence;
+mod rate_limit;
+mod room;
+mod session;
+mod shutdown;
 
-/// Time between each tick update on the server's state
-const STATE_UPDATE_INTERVAL: Duration = Duration::from_millis(333);
+/// Time between each ping used to measure the clients' round trip time
+const PING_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Handles incoming socket connections from clients
 async fn on_connect(socket: SocketRef, State(state): State<Arc<AppState>>) {
-    let state = &state.state;
-    let user_id = Arc::new(Mutex::new(0));
-
-    let socket_state = state.clone();
+    let lobby = state.rooms.lobby();
+    let session = Arc::new(Mutex::new(Session {
+        socket: socket.clone(),
+        room: lobby.clone(),
+        player_id: None,
+        spectating: false,
+        visible: HashSet::new(),
+        rtt: None,
+        rate_limiter: RateLimiter::new(&state.config),
+        chat_limiter: TokenBucket::new(
+            state.config.chat_messages_per_second,
+            state.config.chat_burst,
+        ),
+    }));
+    lobby.enter(&socket, &session);
+    state.sessions.insert(socket.id, session.clone());
 
-    println!("new client connected");
+    info!(socket = %socket.id, "client connected");
 
-    let socket_user_id = user_id.clone();
+    let socket_session = session.clone();
     socket.on(
         ACTION_CHANNEL,
-        async move |socket: SocketRef, Data::<serde_json::Value>(data)| {
-            let event = serde_json::from_value::<netcode::Action>(data);
+        async move |socket: SocketRef,
+                    io: SocketIo,
+                    State(app): State<Arc<AppState>>,
+                    ReceivedSize(size): ReceivedSize,
+                    Data::<serde_json::Value>(data)| {
+            // Actions still queued after a flooding client was disconnected are ignored
+            if !socket.connected() {
+                return;
+            }
+            // Drop excess actions before they are parsed or reach the room's state
+            let allowed = socket_session.lock().unwrap().rate_limiter.check(size);
+            if let Err(err) = allowed {
+                METRICS.record_rate_limited(&err);
+                if err == RateLimitError::Flooding {
+                    warn!(socket = %socket.id, "disconnecting flooding client");
+                    let notice = serde_json::to_string(&DisconnectNotice::RateLimited).unwrap();
+                    let _ = socket.emit(DISCONNECT_NOTICE_CHANNEL, &notice);
+                    let _ = socket.disconnect();
+                } else {
+                    debug!(socket = %socket.id, %err, "action dropped");
+                }
+                return;
+            }
+
+            let event = serde_json::from_value::<Action>(data);
 
             let event = match event {
                 Ok(e) => e,
                 Err(err) => {
+                    METRICS.record_parse_error();
+                    warn!(socket = %socket.id, %err, "could not parse action");
                     let _ = socket.emit(
                         ERROR_CHANNEL,
                         &format!("Error while parsing event payload: {}", err),
@@ -40,94 +102,323 @@ async fn on_connect(socket: SocketRef, State(state): State<Arc<AppState>>) {
                 }
             };
 
-            {
-                println!("Acquiring lock");
-                let mut state = socket_state.lock().unwrap();
-                println!("Lock acquired");
+            METRICS.record_action(&event);
+            let mut session = socket_session.lock().unwrap();
+            let _span = session.span().entered();
+            // Room whose join queue is admitted once the session is unlocked, as admitting
+            // locks the queued sockets' sessions
+            let mut admit = None;
 
-                match event {
-                    netcode::Action::Join => {
-                        let player_id = state.player_join();
-                        {
-                            *socket_user_id.lock().unwrap() = player_id;
+            match event {
+                Action::Join(request) => {
+                    if let Some(player_id) = session.player_id {
+                        emit_error(&socket, SessionError::AlreadyJoined(player_id));
+                        return;
+                    }
+                    let room = session.room.clone();
+                    match room.join(&socket, &mut session, &socket_session, request) {
+                        Ok(response) => {
+                            info!(
+                                player_id = response.player_id,
+                                name = response.name,
+                                "player joined"
+                            );
+                            let response = serde_json::to_string(&response).unwrap();
+                            tokio::spawn(socket.local().emit(JOIN_CHANNEL, &response));
+                        }
+                        Err(rejection) => {
+                            info!(
+                                reason = %rejection.reason,
+                                queue_position = ?rejection.queue_position,
+                                "join rejected"
+                            );
+                            let rejection = serde_json::to_string(&rejection).unwrap();
+                            tokio::spawn(socket.local().emit(JOIN_REJECTED_CHANNEL, &rejection));
                         }
-                        let response =
-                            serde_json::to_string(&JoinResponse::new(player_id)).unwrap();
-                        println!("Player joined the game. Got ID {player_id}");
-                        tokio::spawn(socket.local().emit(JOIN_CHANNEL, &response));
                     }
-                    netcode::Action::Player {
-                        id: player_id,
-                        action,
-                    } => match action {
+                }
+                Action::Player { action } => {
+                    // Sockets can only act on the player they own
+                    let player_id = match session.player() {
+                        Ok(player_id) => player_id,
+                        Err(err) => {
+                            emit_error(&socket, err);
+                            return;
+                        }
+                    };
+                    match &action {
                         netcode::event::PlayerAction::Jump { at } => {
-                            println!("Player {player_id} jumped at {at}");
-                            try_action(state.player_jump(player_id, at), socket);
+                            trace!(player_id, %at, "player jumped");
+                        }
+                        netcode::event::PlayerAction::Move { delta_x, .. } => {
+                            trace!(player_id, delta_x, "player moved");
+                        }
+                        netcode::event::PlayerAction::Tag { target, seen_at } => {
+                            debug!(player_id, target, %seen_at, "player tagged");
+                        }
+                    }
+                    try_action(session.room.player_action(player_id, action), socket);
+                }
+                Action::CreateRoom { max_players } => {
+                    match app.rooms.create(&app.config, max_players) {
+                        Ok(room) => {
+                            info!(new_room = %room.id, "room created");
+                            admit = switch_room(&socket, &app, &mut session, &socket_session, room);
+                        }
+                        Err(err) => emit_error(&socket, err),
+                    }
+                }
+                Action::ListRooms => {
+                    let response = RoomResponse::List {
+                        rooms: app.rooms.list(),
+                    };
+                    emit_room_response(&socket, &response);
+                }
+                Action::JoinRoom { room_id } => match app.rooms.get(&room_id) {
+                    Some(room) => {
+                        admit = switch_room(&socket, &app, &mut session, &socket_session, room);
+                    }
+                    None => {
+                        emit_error(&socket, RoomError::UnknownRoom(room_id));
+                    }
+                },
+                Action::Spectate => {
+                    if let Some(player_id) = session.player_id {
+                        emit_error(&socket, SessionError::AlreadyJoined(player_id));
+                        return;
+                    }
+                    let room = session.room.clone();
+                    let left_queue = room.spectate(&socket, &mut session);
+                    info!("spectating");
+                    let response = RoomResponse::Spectating {
+                        config: room.lock_state().config,
+                    };
+                    emit_room_response(&socket, &response);
+                    if left_queue {
+                        admit = Some(room);
+                    }
+                }
+                Action::Pong { sent_at } => {
+                    session.rtt = (Utc::now() - sent_at).to_std().ok();
+                }
+                Action::Chat { text } => {
+                    let player_id = match session.player() {
+                        Ok(player_id) => player_id,
+                        Err(err) => {
+                            emit_error(&socket, err);
+                            return;
                         }
-                        netcode::event::PlayerAction::Move { delta_x, id } => {
-                            println!("Player {player_id} moved by {delta_x} units");
-                            try_action(state.player_move(player_id, delta_x, id), socket);
+                    };
+                    match chat_message(&mut session, player_id, &text) {
+                        Ok(message) => {
+                            debug!(player_id, text = message.text, "chat message");
+                            let message = serde_json::to_string(&message).unwrap();
+                            let room_id = session.room.id.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = io.to(room_id).emit(CHAT_CHANNEL, &message).await {
+                                    warn!(%e, "failed to broadcast chat message");
+                                }
+                            });
                         }
-                    },
+                        Err(err) => emit_error(&socket, err),
+                    }
                 }
             }
+            drop(session);
+            if let Some(room) = admit {
+                room.admit_queued();
+            }
         },
     );
 
-    let disconnect_state = state.clone();
-    socket.on_disconnect(async move |socket: SocketRef, _: DisconnectReason| {
-        let user_id = *user_id.lock().unwrap();
-        println!("Player {user_id} left the session");
-        let mut state = disconnect_state.lock().unwrap();
-        try_action(state.player_leave(user_id), socket);
-    });
+    socket.on_disconnect(
+        async move |socket: SocketRef,
+                    State(app): State<Arc<AppState>>,
+                    reason: DisconnectReason| {
+            app.sessions.remove(&socket.id);
+            let mut session = session.lock().unwrap();
+            let _span = session.span().entered();
+            info!(?reason, "client disconnected");
+            let room = session.room.clone();
+            let exit = room.exit(&socket, &mut session);
+            drop(session);
+            if exit.freed {
+                room.admit_queued();
+            }
+            if exit.empty {
+                app.rooms.remove(&room.id);
+            }
+        },
+    );
+}
+
+/// Moves a socket into another room, removing its player from the room it leaves.
+/// Rooms that are left empty are closed. Returns the room left if its join queue must be
+/// admitted, once the socket's session is unlocked.
+fn switch_room(
+    socket: &SocketRef,
+    app: &AppState,
+    session: &mut Session,
+    handle: &SharedSession,
+    room: Arc<Room>,
+) -> Option<Arc<Room>> {
+    let left = session.room.clone();
+    let exit = left.exit(socket, session);
+    if exit.empty {
+        app.rooms.remove(&left.id);
+    }
+    room.enter(socket, handle);
+    session.room = room;
+
+    let response = RoomResponse::Joined {
+        room_id: session.room.id.clone(),
+    };
+    emit_room_response(socket, &response);
+    exit.freed.then_some(left)
+}
+
+/// Builds the chat message sent by a player, checking its length and the player's chat rate
+/// limit. The message is stamped with the player's name and the server's time.
+fn chat_message(
+    session: &mut Session,
+    player_id: usize,
+    text: &str,
+) -> Result<ChatMessage, ChatError> {
+    let text = ChatMessage::validate(text)?;
+    if !session.chat_limiter.try_take(1.0) {
+        return Err(ChatError::RateLimited);
+    }
+    let name = session
+        .room
+        .lock_state()
+        .players
+        .get(&player_id)
+        .map(|player| player.name.clone())
+        .unwrap_or_default();
+    Ok(ChatMessage {
+        player_id,
+        name,
+        text: text.to_string(),
+        sent_at: Utc::now(),
+    })
+}
+
+/// Sends a room response to the socket, without blocking the thread.
+fn emit_room_response(socket: &SocketRef, response: &RoomResponse) {
+    let response = serde_json::to_string(response).unwrap();
+    tokio::spawn(socket.local().emit(ROOM_CHANNEL, &response));
 }
 
 /// Tries applying a state action; sends a message to the error channel if it fails, without blocking the thread.
 fn try_action(result: Result<(), netcode::state::StateError>, socket: SocketRef) {
     if let Err(e) = result {
-        tokio::spawn(socket.local().emit(ERROR_CHANNEL, &e.to_string()));
+        METRICS.record_state_error(&e);
+        emit_error(&socket, e);
     }
 }
 
-/// Global game state that can be cloned into multiple handles across threads.
-#[derive(Debug, Clone, Default)]
+/// Sends an error to the socket's error channel, without blocking the thread.
+fn emit_error(socket: &SocketRef, err: impl std::error::Error) {
+    debug!(%err, "action rejected");
+    tokio::spawn(socket.local().emit(ERROR_CHANNEL, &err.to_string()));
+}
+
+/// Global server state that can be cloned into multiple handles across threads.
+#[derive(Debug)]
 struct AppState {
-    state: Arc<Mutex<netcode::State>>,
+    rooms: Rooms,
+    sessions: Sessions,
+    config: Config,
+    log_filter: LogFilter,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// Server state with the default room open, without any socket or logger
+    fn test(config: Config) -> Arc<Self> {
+        let state = Arc::new(AppState {
+            rooms: Rooms::new(&config),
+            sessions: Sessions::default(),
+            config,
+            log_filter: LogFilter::detached(),
+        });
+        state.rooms.open(&state.config, Default::default());
+        state
+    }
+}
+
+/// Starts pinging every client periodically to measure their round trip time, until the rooms
+/// are stopped
+fn start_periodic_ping(io: SocketIo, mut stopped: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PING_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stopped.wait_for(|stopped| *stopped) => return,
+            }
+            let sent_at = serde_json::to_string(&Utc::now()).unwrap();
+            if let Err(e) = io.emit(PING_CHANNEL, &sent_at).await {
+                warn!(%e, "failed to ping the clients");
+            }
+        }
+    });
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let state = Arc::new(AppState::default());
+    let config = Config::load(Cli::parse())?;
+    let log_filter = logging::init(&config.log_filter)?;
+    info!("effective config:\n{}", toml::to_string_pretty(&config)?);
+    let saved = match &config.world_save {
+        Some(path) => persistence::load(path)?,
+        None => None,
+    };
+    if let Some(saved) = &saved {
+        info!(saved_at = %saved.saved_at, rooms = saved.rooms.len(), "restoring the saved world");
+    }
+
+    let state = Arc::new(AppState {
+        rooms: Rooms::new(&config),
+        sessions: Sessions::default(),
+        config,
+        log_filter,
+    });
 
     let (layer, io) = SocketIo::builder().with_state(state.clone()).build_layer();
+    let saved_rooms = saved.map(|saved| saved.rooms).unwrap_or_default();
+    state.rooms.open(&state.config, saved_rooms);
+    if let Some(path) = &state.config.world_save {
+        persistence::start_periodic_save(state.clone(), path.clone());
+    }
     io.ns("/", on_connect);
+    start_periodic_ping(io.clone(), state.rooms.stopped());
 
-    start_periodic_broadcast_to_namespace(io.clone(), state.clone());
-
-    println!("Creating router");
-
-    let app = axum::Router::new().layer(layer);
+    let metrics_state = state.clone();
+    let mut app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(async move || METRICS.render(&metrics_state)),
+    );
+    match &state.config.admin_token {
+        Some(token) => {
+            app = app.nest("/admin", admin::router(state.clone(), token.clone()));
+        }
+        None => info!("no admin token set, the admin API is disabled"),
+    }
+    let app = app.layer(layer);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:7878").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener =
+        tokio::net::TcpListener::bind((state.config.host.as_str(), state.config.port)).await?;
+    info!(address = %listener.local_addr()?, "listening");
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let signal = shutdown::signal().await;
+            info!(signal, "shutting down");
+            let reason = format!("the server received {signal}");
+            shutdown::shutdown(&state, &io, reason).await;
+        })
+        .await?;
 
     Ok(())
 }
-
-// Starts broadcasting the state periodically to all clients to synchronize the game state.
-fn start_periodic_broadcast_to_namespace(io: SocketIo, state: Arc<AppState>) {
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(STATE_UPDATE_INTERVAL);
-
-        loop {
-            interval.tick().await;
-
-            let message = state.state.lock().unwrap().tick();
-
-            if let Err(e) = io.broadcast().emit(STATE_CHANNEL, &message).await {
-                eprintln!("Failed to broadcast to namespace /: {}", e);
-            }
-        }
-    });
-}
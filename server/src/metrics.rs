@@ -0,0 +1,346 @@
+//! Server metrics, exposed in the Prometheus text format on `/metrics`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use netcode::{event::PlayerAction, state::StateError, Action};
+
+use crate::{rate_limit::RateLimitError, AppState};
+
+/// Metrics recorded by the whole server
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Bucket bounds, in seconds, of the histograms measuring short durations
+const DURATION_BUCKETS: &[f64] = &[
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1,
+];
+
+/// Bucket bounds, in bytes, of the histograms measuring payload sizes
+const SIZE_BUCKETS: &[f64] = &[64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0];
+
+/// Counters, histograms and gauges describing the server's health
+#[derive(Debug)]
+pub struct Metrics {
+    actions: LabeledCounter,
+    parse_errors: AtomicU64,
+    state_errors: LabeledCounter,
+    rate_limited: LabeledCounter,
+    tick_duration: Histogram,
+    broadcast_bytes: Histogram,
+    state_lock_wait: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            actions: LabeledCounter::default(),
+            parse_errors: AtomicU64::new(0),
+            state_errors: LabeledCounter::default(),
+            rate_limited: LabeledCounter::default(),
+            tick_duration: Histogram::new(DURATION_BUCKETS),
+            broadcast_bytes: Histogram::new(SIZE_BUCKETS),
+            state_lock_wait: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts an action received from a client, by type
+    pub fn record_action(&self, action: &Action) {
+        let kind = match action {
+            Action::Join(_) => "join",
+            Action::Player {
+                action: PlayerAction::Jump { .. },
+            } => "jump",
+            Action::Player {
+                action: PlayerAction::Move { .. },
+            } => "move",
+            Action::Player {
+                action: PlayerAction::Tag { .. },
+            } => "tag",
+            Action::CreateRoom { .. } => "create_room",
+            Action::ListRooms => "list_rooms",
+            Action::JoinRoom { .. } => "join_room",
+            Action::Pong { .. } => "pong",
+            Action::Chat { .. } => "chat",
+            Action::Spectate => "spectate",
+        };
+        self.actions.increment(kind);
+    }
+
+    /// Counts an action payload that could not be parsed
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an action rejected by the game state, by error variant
+    pub fn record_state_error(&self, err: &StateError) {
+        let variant = match err {
+            StateError::UnknownPlayer(_) => "unknown_player",
+            StateError::Cheating { .. } => "cheating",
+            StateError::NotIt(_) => "not_it",
+            StateError::TagSelf(_) => "tag_self",
+            StateError::TagCooldown { .. } => "tag_cooldown",
+            StateError::TagMissed { .. } => "tag_missed",
+        };
+        self.state_errors.increment(variant);
+    }
+
+    /// Counts an action refused by a socket's rate limiter, by reason
+    pub fn record_rate_limited(&self, err: &RateLimitError) {
+        let reason = match err {
+            RateLimitError::Messages => "messages",
+            RateLimitError::Bytes => "bytes",
+            RateLimitError::Flooding => "flooding",
+        };
+        self.rate_limited.increment(reason);
+    }
+
+    /// Records how long a simulation tick took
+    pub fn record_tick(&self, duration: Duration) {
+        self.tick_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Records the size of a state snapshot broadcast to a room
+    pub fn record_broadcast(&self, bytes: usize) {
+        self.broadcast_bytes.observe(bytes as f64);
+    }
+
+    /// Records how long it took to acquire a room's state lock
+    pub fn record_state_lock_wait(&self, duration: Duration) {
+        self.state_lock_wait.observe(duration.as_secs_f64());
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self, app: &AppState) -> String {
+        let mut out = String::new();
+
+        gauge_header(
+            &mut out,
+            "netcube_connected_sockets",
+            "Number of connected sockets",
+        );
+        let _ = writeln!(out, "netcube_connected_sockets {}", app.sessions.len());
+
+        // Human and AI players are counted apart, so AI players do not pass for joined clients
+        let players = app
+            .rooms
+            .all()
+            .iter()
+            .map(|room| {
+                let state = room.lock_state();
+                let ai = state.ai_count();
+                (room.id.clone(), state.players.len() - ai, ai)
+            })
+            .collect::<Vec<_>>();
+        gauge_header(
+            &mut out,
+            "netcube_joined_players",
+            "Number of human players that joined a room's game",
+        );
+        for (room_id, humans, _) in &players {
+            let _ = writeln!(out, "netcube_joined_players{{room=\"{room_id}\"}} {humans}");
+        }
+        gauge_header(
+            &mut out,
+            "netcube_ai_players",
+            "Number of AI players in a room's game",
+        );
+        for (room_id, _, ai) in &players {
+            let _ = writeln!(out, "netcube_ai_players{{room=\"{room_id}\"}} {ai}");
+        }
+
+        gauge_header(
+            &mut out,
+            "netcube_spectators",
+            "Number of sockets spectating a room's game",
+        );
+        for room in app.rooms.list() {
+            let _ = writeln!(
+                out,
+                "netcube_spectators{{room=\"{}\"}} {}",
+                room.id, room.spectators
+            );
+        }
+
+        self.actions.render(
+            &mut out,
+            "netcube_actions_received_total",
+            "Actions received from the clients, by type",
+            "type",
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP netcube_parse_errors_total Action payloads that could not be parsed"
+        );
+        let _ = writeln!(out, "# TYPE netcube_parse_errors_total counter");
+        let _ = writeln!(
+            out,
+            "netcube_parse_errors_total {}",
+            self.parse_errors.load(Ordering::Relaxed)
+        );
+
+        self.state_errors.render(
+            &mut out,
+            "netcube_state_errors_total",
+            "Actions rejected by the game state, by error variant",
+            "variant",
+        );
+
+        self.rate_limited.render(
+            &mut out,
+            "netcube_rate_limited_total",
+            "Actions refused by the rate limiter, by reason",
+            "reason",
+        );
+
+        self.tick_duration.render(
+            &mut out,
+            "netcube_tick_duration_seconds",
+            "Time taken by a room's simulation tick",
+        );
+        self.broadcast_bytes.render(
+            &mut out,
+            "netcube_broadcast_payload_bytes",
+            "Size of the state snapshots broadcast to the rooms",
+        );
+        self.state_lock_wait.render(
+            &mut out,
+            "netcube_state_lock_wait_seconds",
+            "Time spent waiting to acquire a room's state lock",
+        );
+
+        out
+    }
+}
+
+fn gauge_header(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} gauge");
+}
+
+/// A counter split by the value of a single label
+#[derive(Debug, Default)]
+struct LabeledCounter {
+    counts: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl LabeledCounter {
+    fn increment(&self, label: &'static str) {
+        *self.counts.lock().unwrap().entry(label).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (value, count) in self.counts.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {count}");
+        }
+    }
+}
+
+/// A histogram of observed values over fixed buckets
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Debug)]
+struct HistogramData {
+    /// Number of observations that fell in each bucket, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            data: Mutex::new(HistogramData {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            data.buckets[bucket] += 1;
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let data = self.data.lock().unwrap();
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&data.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", data.count);
+        let _ = writeln!(out, "{name}_sum {}", data.sum);
+        let _ = writeln!(out, "{name}_count {}", data.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use netcode::state::fixture;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn test_render() {
+        let app = AppState::test(Config {
+            ai_players: vec![netcode::ai::AiBehaviour::Jump],
+            ..Default::default()
+        });
+        fixture::join(&mut app.rooms.lobby().lock_state(), "Alice");
+
+        let metrics = Metrics::default();
+        metrics.record_action(&Action::ListRooms);
+        metrics.record_action(&Action::ListRooms);
+        metrics.record_rate_limited(&RateLimitError::Flooding);
+        metrics.record_tick(Duration::from_millis(2));
+        let rendered = metrics.render(&app);
+
+        for line in [
+            "netcube_connected_sockets 0",
+            "netcube_joined_players{room=\"lobby\"} 1",
+            "netcube_ai_players{room=\"lobby\"} 1",
+            "netcube_spectators{room=\"lobby\"} 0",
+            "# TYPE netcube_actions_received_total counter",
+            "netcube_actions_received_total{type=\"list_rooms\"} 2",
+            "netcube_parse_errors_total 0",
+            "netcube_rate_limited_total{reason=\"flooding\"} 1",
+            "# TYPE netcube_tick_duration_seconds histogram",
+            "netcube_tick_duration_seconds_bucket{le=\"0.001\"} 0",
+            "netcube_tick_duration_seconds_bucket{le=\"0.005\"} 1",
+            "netcube_tick_duration_seconds_bucket{le=\"0.1\"} 1",
+            "netcube_tick_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "netcube_tick_duration_seconds_count 1",
+            "netcube_broadcast_payload_bytes_count 0",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "missing {line}"
+            );
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Saves of the world to a local file, written periodically and on shutdown and restored at
+//! startup, so a restart does not lose the rooms' players.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use netcode::{event::RoomId, state::SavedState};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use crate::AppState;
+
+/// Version of the world save format written by this server
+pub const WORLD_SAVE_VERSION: u32 = 1;
+
+/// Represents all possible errors that can occur when saving or restoring the world
+#[derive(thiserror::Error, Debug)]
+pub enum PersistenceError {
+    #[error("[ERROR - WORLD SAVE] Could not read world save {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("[ERROR - WORLD SAVE] Could not parse world save {0}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("[ERROR - WORLD SAVE] Unsupported world save version {found}, expected {expected}.")]
+    Version { found: u32, expected: u32 },
+    #[error("[ERROR - WORLD SAVE] Could not serialize the world: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("[ERROR - WORLD SAVE] Could not write world save {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+/// The saved game of every room, by room id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorldSave {
+    pub version: u32,
+    pub saved_at: DateTime<Utc>,
+    pub rooms: BTreeMap<RoomId, SavedState>,
+}
+
+/// Reads the world save, if the file exists
+pub fn load(path: &Path) -> Result<Option<WorldSave>, PersistenceError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(PersistenceError::Read(path.to_path_buf(), err)),
+    };
+    let save: WorldSave = serde_json::from_str(&content)
+        .map_err(|err| PersistenceError::Parse(path.to_path_buf(), err))?;
+    if save.version != WORLD_SAVE_VERSION {
+        return Err(PersistenceError::Version {
+            found: save.version,
+            expected: WORLD_SAVE_VERSION,
+        });
+    }
+    Ok(Some(save))
+}
+
+/// Saves the game of every room. The save is written next to the file then moved over it, so
+/// a crash while saving leaves the previous save intact.
+pub fn save(app: &AppState, path: &Path) -> Result<(), PersistenceError> {
+    let save = WorldSave {
+        version: WORLD_SAVE_VERSION,
+        saved_at: Utc::now(),
+        rooms: app.rooms.save(),
+    };
+    let content = serde_json::to_string_pretty(&save)?;
+
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    std::fs::write(&temporary, content)
+        .and_then(|()| std::fs::rename(&temporary, path))
+        .map_err(|err| PersistenceError::Write(path.to_path_buf(), err))
+}
+
+/// Starts saving the world to the given file periodically, until the rooms are stopped
+pub fn start_periodic_save(app: Arc<AppState>, path: PathBuf) {
+    tokio::spawn(async move {
+        let mut stopped = app.rooms.stopped();
+        let mut interval = tokio::time::interval(app.config.save_interval());
+        // The first tick completes immediately, with nothing new to save
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stopped.wait_for(|stopped| *stopped) => return,
+            }
+            match save(&app, &path) {
+                Ok(()) => debug!(path = %path.display(), "world saved"),
+                Err(err) => error!(%err, "could not save the world"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use netcode::{state::fixture::join, DEFAULT_ROOM};
+
+    use super::*;
+    use crate::{config::Config, room::Rooms};
+
+    /// Path of a world save in a directory of its own, removed beforehand
+    fn save_path(test: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("netcube-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.join("world.json")
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = save_path("round-trip");
+        let config = Config::default();
+        let app = AppState::test(config.clone());
+        let lobby = app.rooms.lobby();
+        let alice = {
+            let mut state = lobby.lock_state();
+            let alice = join(&mut state, "Alice");
+            state.players.get_mut(&alice).unwrap().x = -2.0;
+            alice
+        };
+        assert!(load(&path).unwrap().is_none());
+
+        // The second save replaces the first one
+        save(&app, &path).unwrap();
+        join(&mut lobby.lock_state(), "Bob");
+        save(&app, &path).unwrap();
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        assert!(!Path::new(&temporary).exists());
+
+        let loaded = load(&path).unwrap().unwrap();
+        assert_eq!(loaded.version, WORLD_SAVE_VERSION);
+        assert_eq!(loaded.rooms, app.rooms.save());
+        assert_eq!(loaded.rooms[DEFAULT_ROOM].players.len(), 2);
+
+        // Alice returns where she left off in the restored lobby
+        let rooms = Rooms::new(&config);
+        rooms.open(&config, loaded.rooms);
+        let lobby = rooms.lobby();
+        let mut state = lobby.lock_state();
+        assert_eq!(join(&mut state, "Alice"), alice);
+        assert_eq!(state.players[&alice].x, -2.0);
+    }
+
+    #[test]
+    fn test_load_rejects_corrupt_save() {
+        let path = save_path("corrupt");
+        std::fs::write(&path, "{\"version\": 1, \"rooms\": ").unwrap();
+        assert!(matches!(load(&path), Err(PersistenceError::Parse(..))));
+    }
+
+    #[test]
+    fn test_load_rejects_other_version() {
+        let path = save_path("version");
+        let save = WorldSave {
+            version: WORLD_SAVE_VERSION + 1,
+            saved_at: Utc::now(),
+            rooms: BTreeMap::new(),
+        };
+        std::fs::write(&path, serde_json::to_string(&save).unwrap()).unwrap();
+        assert!(matches!(
+            load(&path),
+            Err(PersistenceError::Version { found, expected: WORLD_SAVE_VERSION })
+                if found == WORLD_SAVE_VERSION + 1
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! Per-socket rate limiting of the actions sent by the clients.
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use socketioxide::{adapter::Adapter, handler::FromMessageParts, socket::Socket};
+use socketioxide_core::Value;
+
+use crate::config::Config;
+
+/// Time over which dropped actions are counted to find persistent offenders
+const OFFENSE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Represents all possible reasons for an action to be refused by the rate limiter
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitError {
+    #[error("[ERROR - RATE LIMITED] Too many actions per second, the action was dropped.")]
+    Messages,
+    #[error("[ERROR - RATE LIMITED] Too many bytes per second, the action was dropped.")]
+    Bytes,
+    #[error("[ERROR - RATE LIMITED] Too many actions were dropped, disconnecting.")]
+    Flooding,
+}
+
+/// Allows a steady rate of units per second, with bursts up to the bucket's capacity
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(per_second: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            per_second,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes `amount` units out of the bucket if it has enough of them
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        self.try_take_at(amount, Instant::now())
+    }
+
+    /// Takes `amount` units out of the bucket at the given time, after refilling it for the
+    /// time since the last take
+    fn try_take_at(&mut self, amount: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// Limits the number of actions and bytes a socket can send per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    /// Max number of actions dropped within a second before the socket is disconnected
+    max_dropped: u32,
+    dropped: u32,
+    window_start: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            messages: TokenBucket::new(
+                config.rate_limit_messages_per_second,
+                config.rate_limit_messages_per_second * config.rate_limit_burst,
+            ),
+            bytes: TokenBucket::new(
+                config.rate_limit_bytes_per_second,
+                config.rate_limit_bytes_per_second * config.rate_limit_burst,
+            ),
+            max_dropped: config.rate_limit_max_dropped,
+            dropped: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Takes an action of `size` bytes out of the socket's allowance. Fails with
+    /// [RateLimitError::Flooding] once too many actions were dropped within a second.
+    pub fn check(&mut self, size: usize) -> Result<(), RateLimitError> {
+        self.check_at(size, Instant::now())
+    }
+
+    /// Takes an action of `size` bytes out of the socket's allowance at the given time.
+    /// A dropped action still counts against the number of actions per second.
+    fn check_at(&mut self, size: usize, now: Instant) -> Result<(), RateLimitError> {
+        let refused = if !self.messages.try_take_at(1.0, now) {
+            RateLimitError::Messages
+        } else if !self.bytes.try_take_at(size as f64, now) {
+            RateLimitError::Bytes
+        } else {
+            return Ok(());
+        };
+
+        if now.duration_since(self.window_start) >= OFFENSE_WINDOW {
+            self.window_start = now;
+            self.dropped = 0;
+        }
+        self.dropped += 1;
+        if self.dropped > self.max_dropped {
+            return Err(RateLimitError::Flooding);
+        }
+        Err(refused)
+    }
+}
+
+/// Size in bytes of an action as the client sent it, before it is parsed
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedSize(pub usize);
+
+impl<A: Adapter> FromMessageParts<A> for ReceivedSize {
+    type Error = Infallible;
+
+    fn from_message_parts(
+        _: &Arc<Socket<A>>,
+        value: &mut Value,
+        _: &Option<i64>,
+    ) -> Result<Self, Self::Error> {
+        let size = match value {
+            Value::Str(text, binaries) => {
+                text.len()
+                    + binaries
+                        .iter()
+                        .flatten()
+                        .map(|binary| binary.len())
+                        .sum::<usize>()
+            }
+            Value::Bytes(binary) => binary.len(),
+        };
+        Ok(Self(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(&Config {
+            rate_limit_messages_per_second: 10.0,
+            rate_limit_bytes_per_second: 1000.0,
+            rate_limit_burst: 2.0,
+            rate_limit_max_dropped: 3,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_burst_capacity() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..20 {
+            assert_eq!(limiter.check_at(10, now), Ok(()));
+        }
+        assert_eq!(limiter.check_at(10, now), Err(RateLimitError::Messages));
+    }
+
+    #[test]
+    fn test_refill_over_time() {
+        let mut bucket = TokenBucket::new(10.0, 20.0);
+        let now = Instant::now();
+        assert!(bucket.try_take_at(20.0, now));
+        assert!(!bucket.try_take_at(1.0, now));
+        assert!(bucket.try_take_at(5.0, now + Duration::from_millis(500)));
+        assert!(!bucket.try_take_at(1.0, now + Duration::from_millis(500)));
+        // Never refilled over capacity
+        assert!(!bucket.try_take_at(21.0, now + Duration::from_secs(60)));
+        assert!(bucket.try_take_at(20.0, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_oversized_action() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(2001, now), Err(RateLimitError::Bytes));
+        assert_eq!(limiter.check_at(2000, now), Ok(()));
+        assert_eq!(limiter.check_at(1, now), Err(RateLimitError::Bytes));
+    }
+
+    #[test]
+    fn test_flooding_disconnects() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..20 {
+            limiter.check_at(1, now).unwrap();
+        }
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(1, now), Err(RateLimitError::Messages));
+        }
+        assert_eq!(limiter.check_at(1, now), Err(RateLimitError::Flooding));
+        // Dropped actions are forgiven after a second without flooding
+        let later = now + OFFENSE_WINDOW + Duration::from_millis(100);
+        assert_eq!(limiter.check_at(1, later), Ok(()));
+    }
+}
@@ -0,0 +1,1001 @@
+//! Rooms hosting independent matches, each with its own state, tick loop and broadcast.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use netcode::{
+    ai::AiBehaviour,
+    event::{
+        JoinRejectReason, JoinRejection, JoinRequest, JoinResponse, PlayerAction, RoomId, RoomInfo,
+        RoomResponse,
+    },
+    replay::{ReplayEntry, ReplayError, ReplayEvent, ReplayHeader, ReplayWriter},
+    state::{InterestChanges, SavedState, StateError, WorldConfig},
+    DEFAULT_ROOM, JOIN_CHANNEL, JOIN_REJECTED_CHANNEL, ROOM_CHANNEL, STATE_CHANNEL,
+};
+use socketioxide::{extract::SocketRef, socket::Sid};
+use tokio::{sync::watch, time::Interval};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Config,
+    metrics::METRICS,
+    session::{Session, SharedSession},
+};
+
+/// Represents all possible errors that can occur when moving between rooms
+#[derive(thiserror::Error, Debug)]
+pub enum RoomError {
+    #[error("[ERROR - UNKNOWN ROOM] No room found with id: {0}.")]
+    UnknownRoom(RoomId),
+    #[error("[ERROR - TOO MANY ROOMS] The server already has its max of {0} rooms open.")]
+    TooManyRooms(usize),
+}
+
+/// A socket waiting for a free player slot in a full room
+#[derive(Debug)]
+struct QueuedJoin {
+    socket: SocketRef,
+    session: Weak<Mutex<Session>>,
+    request: JoinRequest,
+}
+
+/// What a socket leaving a room changed
+#[derive(Debug, Clone, Copy)]
+#[must_use]
+pub struct Exit {
+    /// The room has no sockets left
+    pub empty: bool,
+    /// A player slot or a place in the join queue was freed, so the queue must be admitted
+    pub freed: bool,
+}
+
+/// A single match with its own game state and connected sockets
+#[derive(Debug)]
+pub struct Room {
+    pub id: RoomId,
+    state: Mutex<netcode::State>,
+    /// Sessions of the sockets in the room
+    members: Mutex<HashMap<Sid, Weak<Mutex<Session>>>>,
+    spectators: AtomicUsize,
+    join_queue: Option<Mutex<VecDeque<QueuedJoin>>>,
+    /// Distance around a member's player within which other players are sent to it.
+    /// Every member receives the whole state if not set
+    interest_radius: Option<f64>,
+    /// Replay the changes to the room's state are recorded to, if recording.
+    /// Always locked after the state, so entries are written in the order they were applied
+    recorder: Mutex<Option<ReplayWriter<BufWriter<File>>>>,
+    /// Behaviour of each AI player added to the room's game
+    ai_players: Vec<AiBehaviour>,
+}
+
+impl Room {
+    /// Locks the room's game state, recording how long the lock took to acquire
+    pub fn lock_state(&self) -> MutexGuard<'_, netcode::State> {
+        let started = Instant::now();
+        let state = self.state.lock().unwrap();
+        METRICS.record_state_lock_wait(started.elapsed());
+        state
+    }
+
+    /// Records a change to the room's state in its replay, if the room is recorded.
+    /// Recording stops for good if the replay cannot be written.
+    fn record(&self, entry: ReplayEntry) {
+        let mut recorder = self.recorder.lock().unwrap();
+        let Some(writer) = recorder.as_mut() else {
+            return;
+        };
+        if let Err(err) = writer.record(&entry) {
+            error!(room = %self.id, %err, "could not record the replay, recording stopped");
+            *recorder = None;
+        }
+    }
+
+    /// Stops recording the room's game, writing what is left of its replay
+    fn stop_recording(&self) {
+        let Some(writer) = self.recorder.lock().unwrap().take() else {
+            return;
+        };
+        if let Err(err) = writer.finish() {
+            error!(room = %self.id, %err, "could not finish the replay");
+        }
+    }
+
+    /// Adds the room's AI players to its game, as far as its player cap allows
+    fn spawn_ai_players(&self) {
+        let mut state = self.lock_state();
+        for behaviour in &self.ai_players {
+            let Ok(player_id) = state.spawn_ai(*behaviour) else {
+                warn!(room = %self.id, "the room is too small for all of its AI players");
+                return;
+            };
+            self.record(ReplayEntry::new(
+                Some(player_id),
+                ReplayEvent::SpawnAi {
+                    behaviour: *behaviour,
+                },
+            ));
+        }
+    }
+
+    /// Applies an action on a player of the room's game, recording it if it was accepted
+    pub fn player_action(&self, player_id: usize, action: PlayerAction) -> Result<(), StateError> {
+        let mut state = self.lock_state();
+        let at = Utc::now();
+        state.player_action(player_id, &action, at)?;
+        self.record(ReplayEntry {
+            at,
+            player_id: Some(player_id),
+            event: ReplayEvent::Action { action },
+        });
+        Ok(())
+    }
+
+    /// Makes the socket's player join the room's game.
+    /// If the room is full and has a waiting queue, the socket is queued and the rejection
+    /// includes its position in the queue.
+    pub fn join(
+        &self,
+        socket: &SocketRef,
+        session: &mut Session,
+        handle: &SharedSession,
+        request: JoinRequest,
+    ) -> Result<JoinResponse, JoinRejection> {
+        let mut state = self.lock_state();
+        match state.player_join(&request) {
+            Ok(player_id) => {
+                session.player_id = Some(player_id);
+                self.stop_spectating(session);
+                self.record(ReplayEntry::new(
+                    Some(player_id),
+                    ReplayEvent::Join {
+                        request: request.clone(),
+                    },
+                ));
+                Ok(JoinResponse::new(&state.players[&player_id], state.config))
+            }
+            Err(reason @ JoinRejectReason::Full { .. }) => Err(JoinRejection {
+                reason,
+                queue_position: self.enqueue(socket, handle, request),
+            }),
+            Err(reason) => Err(JoinRejection {
+                reason,
+                queue_position: None,
+            }),
+        }
+    }
+
+    /// Adds a socket to the waiting queue, if the room has one. A socket that is already queued
+    /// keeps its position, with its latest request.
+    /// Returns the socket's position in the queue, starting at 1.
+    fn enqueue(
+        &self,
+        socket: &SocketRef,
+        handle: &SharedSession,
+        request: JoinRequest,
+    ) -> Option<usize> {
+        let mut queue = self.join_queue.as_ref()?.lock().unwrap();
+        let position = match queue
+            .iter()
+            .position(|queued| queued.socket.id == socket.id)
+        {
+            Some(index) => {
+                queue[index].request = request;
+                index
+            }
+            None => {
+                queue.push_back(QueuedJoin {
+                    socket: socket.clone(),
+                    session: Arc::downgrade(handle),
+                    request,
+                });
+                queue.len() - 1
+            }
+        };
+        Some(position + 1)
+    }
+
+    /// Fills free player slots from the join queue, then tells the sockets still waiting their
+    /// new position.
+    pub fn admit_queued(self: &Arc<Self>) {
+        let Some(queue) = &self.join_queue else {
+            return;
+        };
+        self.admit_next(queue);
+        self.notify_queue_positions(queue);
+    }
+
+    /// Admits queued sockets in order while the room has free player slots
+    fn admit_next(self: &Arc<Self>, queue: &Mutex<VecDeque<QueuedJoin>>) {
+        while !self.lock_state().is_full() {
+            // The queue lock is released before locking the queued socket's session
+            let Some(queued) = queue.lock().unwrap().pop_front() else {
+                return;
+            };
+            let Some(session) = queued.session.upgrade() else {
+                continue;
+            };
+            let mut session = session.lock().unwrap();
+            if session.player_id.is_some() || !Arc::ptr_eq(&session.room, self) {
+                continue;
+            }
+
+            let mut state = self.lock_state();
+            let player_id = match state.player_join(&queued.request) {
+                Ok(player_id) => player_id,
+                Err(JoinRejectReason::Full { .. }) => {
+                    drop(state);
+                    queue.lock().unwrap().push_front(queued);
+                    return;
+                }
+                // The name was taken while the socket was waiting
+                Err(reason) => {
+                    let rejection = serde_json::to_string(&JoinRejection {
+                        reason,
+                        queue_position: None,
+                    })
+                    .unwrap();
+                    tokio::spawn(
+                        queued
+                            .socket
+                            .local()
+                            .emit(JOIN_REJECTED_CHANNEL, &rejection),
+                    );
+                    continue;
+                }
+            };
+            session.player_id = Some(player_id);
+            self.record(ReplayEntry::new(
+                Some(player_id),
+                ReplayEvent::Join {
+                    request: queued.request.clone(),
+                },
+            ));
+            self.stop_spectating(&mut session);
+
+            let response =
+                serde_json::to_string(&JoinResponse::new(&state.players[&player_id], state.config))
+                    .unwrap();
+            info!(room = %self.id, socket = %queued.socket.id, player_id, "player admitted from the queue");
+            tokio::spawn(queued.socket.local().emit(JOIN_CHANNEL, &response));
+        }
+    }
+
+    /// Sends every queued socket its current position in the queue
+    fn notify_queue_positions(&self, queue: &Mutex<VecDeque<QueuedJoin>>) {
+        let Some(max_players) = self.lock_state().config.max_players else {
+            return;
+        };
+        for (index, queued) in queue.lock().unwrap().iter().enumerate() {
+            let rejection = JoinRejection {
+                reason: JoinRejectReason::Full { max_players },
+                queue_position: Some(index + 1),
+            };
+            let rejection = serde_json::to_string(&rejection).unwrap();
+            tokio::spawn(
+                queued
+                    .socket
+                    .local()
+                    .emit(JOIN_REJECTED_CHANNEL, &rejection),
+            );
+        }
+    }
+
+    /// Adds a socket to the room so it receives the room's state broadcasts
+    pub fn enter(&self, socket: &SocketRef, session: &SharedSession) {
+        socket.join(self.id.clone());
+        self.members
+            .lock()
+            .unwrap()
+            .insert(socket.id, Arc::downgrade(session));
+    }
+
+    /// Makes the socket spectate the room's game, taking it out of the join queue.
+    /// Spectators have no player, so they are not bound by the room's player cap.
+    /// Returns true if the socket left the join queue, in which case the caller calls
+    /// [Room::admit_queued] once it has unlocked the socket's session.
+    pub fn spectate(&self, socket: &SocketRef, session: &mut Session) -> bool {
+        if std::mem::replace(&mut session.spectating, true) {
+            return false;
+        }
+        self.spectators.fetch_add(1, Ordering::SeqCst);
+        self.dequeue(socket)
+    }
+
+    /// Stops counting the socket as a spectator, when it joins the game or leaves the room
+    fn stop_spectating(&self, session: &mut Session) {
+        if std::mem::take(&mut session.spectating) {
+            self.spectators.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Removes a socket from the waiting queue. Returns true if it was queued.
+    fn dequeue(&self, socket: &SocketRef) -> bool {
+        self.join_queue.as_ref().is_some_and(|queue| {
+            let mut queue = queue.lock().unwrap();
+            let len = queue.len();
+            queue.retain(|queued| queued.socket.id != socket.id);
+            queue.len() != len
+        })
+    }
+
+    /// Removes a socket and its player from the room.
+    /// The join queue is not admitted here, as admitting locks the queued sockets' sessions:
+    /// the caller calls [Room::admit_queued] once it has unlocked the leaving socket's session.
+    pub fn exit(&self, socket: &SocketRef, session: &mut Session) -> Exit {
+        socket.leave(self.id.clone());
+        self.stop_spectating(session);
+        session.visible.clear();
+        let was_queued = self.dequeue(socket);
+        let player_id = session.player_id.take();
+        if let Some(player_id) = player_id {
+            let mut state = self.lock_state();
+            if state.player_leave(player_id).is_ok() {
+                self.record(ReplayEntry::new(Some(player_id), ReplayEvent::Leave));
+            }
+        }
+        let mut members = self.members.lock().unwrap();
+        members.remove(&socket.id);
+        Exit {
+            empty: members.is_empty(),
+            freed: was_queued || player_id.is_some(),
+        }
+    }
+
+    /// Replaces the room's game with a new one under the same rules, removing every player.
+    /// The sessions of the room's sockets are reset along with the game, and their clients told
+    /// to start over as if they had just entered the room, so a join cannot slip in between.
+    /// The AI players are added back, then queued sockets are admitted into the emptied game.
+    pub fn reset(self: &Arc<Self>) {
+        let mut members = self
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(sid, session)| Some((*sid, session.upgrade()?)))
+            .collect::<Vec<_>>();
+        // Sessions are locked before the state, in a fixed order
+        members.sort_unstable_by_key(|(sid, _)| *sid);
+        let response = serde_json::to_string(&RoomResponse::Joined {
+            room_id: self.id.clone(),
+        })
+        .unwrap();
+        {
+            let mut sessions = members
+                .iter()
+                .map(|(_, session)| session.lock().unwrap())
+                .collect::<Vec<_>>();
+            let mut state = self.lock_state();
+            *state = netcode::State::new(state.config);
+            self.record(ReplayEntry {
+                at: state.timestamp,
+                player_id: None,
+                event: ReplayEvent::Reset,
+            });
+            for session in &mut sessions {
+                session.player_id = None;
+                session.visible.clear();
+                self.stop_spectating(session);
+                if let Err(e) = session.socket.emit(ROOM_CHANNEL, &response) {
+                    warn!(room = %self.id, socket = %session.socket.id, %e, "failed to notify the reset");
+                }
+            }
+        }
+        self.spawn_ai_players();
+        self.admit_queued();
+    }
+
+    /// Public information about the room
+    pub fn info(&self) -> RoomInfo {
+        let state = self.lock_state();
+        RoomInfo {
+            id: self.id.clone(),
+            players: state.players.len(),
+            max_players: state.config.max_players,
+            spectators: self.spectators.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Tick and broadcast intervals of every room, which can be changed while the server runs
+#[derive(Debug)]
+pub struct Intervals {
+    tick: watch::Sender<Duration>,
+    broadcast: watch::Sender<Duration>,
+}
+
+impl Intervals {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            tick: watch::Sender::new(config.tick_interval()),
+            broadcast: watch::Sender::new(config.broadcast_interval()),
+        }
+    }
+
+    /// Time between each tick update on the rooms' state
+    pub fn tick(&self) -> Duration {
+        *self.tick.borrow()
+    }
+
+    /// Time between each state broadcast to the clients
+    pub fn broadcast(&self) -> Duration {
+        *self.broadcast.borrow()
+    }
+
+    /// Changes the time between each tick update of all rooms, effective immediately
+    pub fn set_tick(&self, interval: Duration) {
+        self.tick.send_replace(interval);
+    }
+
+    /// Changes the time between each state broadcast of all rooms, effective immediately
+    pub fn set_broadcast(&self, interval: Duration) {
+        self.broadcast.send_replace(interval);
+    }
+}
+
+/// All rooms hosted by the server
+#[derive(Debug)]
+pub struct Rooms {
+    rooms: Mutex<HashMap<RoomId, Arc<Room>>>,
+    next_room_id: AtomicUsize,
+    pub intervals: Intervals,
+    stopped: watch::Sender<bool>,
+}
+
+impl Rooms {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rooms: Default::default(),
+            next_room_id: Default::default(),
+            intervals: Intervals::new(config),
+            stopped: watch::Sender::new(false),
+        }
+    }
+
+    /// Stops the tick and broadcast loops and the replay recordings of every room, for good
+    pub fn stop(&self) {
+        self.stopped.send_replace(true);
+        for room in self.all() {
+            room.stop_recording();
+        }
+    }
+
+    /// Receiver that turns true once the rooms are stopped
+    pub fn stopped(&self) -> watch::Receiver<bool> {
+        self.stopped.subscribe()
+    }
+
+    /// Copies of the game state of every room, by room id
+    pub fn snapshot(&self) -> BTreeMap<RoomId, netcode::State> {
+        self.all()
+            .iter()
+            .map(|room| (room.id.clone(), room.lock_state().clone()))
+            .collect()
+    }
+
+    /// Saves the game of every room, by room id
+    pub fn save(&self) -> BTreeMap<RoomId, SavedState> {
+        self.all()
+            .iter()
+            .map(|room| (room.id.clone(), room.lock_state().save()))
+            .collect()
+    }
+
+    /// Opens the default room every client is placed in when connecting, restoring the rooms of
+    /// a saved world. Other rooms are only restored if they had players, and keep their own
+    /// player cap. Must be called before accepting any connections.
+    pub fn open(&self, config: &Config, saved: BTreeMap<RoomId, SavedState>) {
+        for (id, saved) in saved {
+            let max_players = if id == DEFAULT_ROOM {
+                config.max_players
+            } else if saved.players.is_empty() {
+                continue;
+            } else {
+                saved.config.max_players
+            };
+            // New rooms are numbered after the restored ones
+            if let Some(number) = id
+                .strip_prefix("room-")
+                .and_then(|number| number.parse().ok())
+            {
+                self.next_room_id.fetch_max(number, Ordering::SeqCst);
+            }
+            info!(room = %id, players = saved.players.len(), "restoring the room");
+            let state = netcode::State::restore(SavedState {
+                config: WorldConfig {
+                    max_players,
+                    ..config.world()
+                },
+                ..saved
+            });
+            self.insert(id, config, state);
+        }
+
+        if self.get(DEFAULT_ROOM).is_none() {
+            let state = netcode::State::new(config.world());
+            self.insert(DEFAULT_ROOM.to_string(), config, state);
+        }
+    }
+
+    /// The default room every client is placed in when connecting
+    pub fn lobby(&self) -> Arc<Room> {
+        self.get(DEFAULT_ROOM)
+            .expect("the default room is opened at startup and never removed")
+    }
+
+    /// Get a room by id
+    pub fn get(&self, room_id: &str) -> Option<Arc<Room>> {
+        self.rooms.lock().unwrap().get(room_id).cloned()
+    }
+
+    /// Creates a new room with a unique id and starts its tick and broadcast loops.
+    /// The room's player cap is at least 1 and at most the server's, which it defaults to.
+    /// Returns [RoomError::TooManyRooms] if the server has reached its max number of rooms.
+    pub fn create(
+        &self,
+        config: &Config,
+        max_players: Option<usize>,
+    ) -> Result<Arc<Room>, RoomError> {
+        let max_players = match (max_players, config.max_players) {
+            (Some(requested), Some(max_players)) => Some(requested.clamp(1, max_players)),
+            (Some(requested), None) => Some(requested.max(1)),
+            (None, max_players) => max_players,
+        };
+        // The rooms stay locked until the new room is added, so the cap cannot be overshot
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.len() >= config.max_rooms {
+            return Err(RoomError::TooManyRooms(config.max_rooms));
+        }
+        let id = format!(
+            "room-{}",
+            self.next_room_id.fetch_add(1, Ordering::SeqCst) + 1
+        );
+        let state = netcode::State::new(WorldConfig {
+            max_players,
+            ..config.world()
+        });
+        let room = self.start(id.clone(), config, state);
+        rooms.insert(id, room.clone());
+        Ok(room)
+    }
+
+    /// Removes a room unless it is the default room.
+    /// The room's loops stop once the last handle to it is dropped.
+    pub fn remove(&self, room_id: &str) {
+        if room_id != DEFAULT_ROOM {
+            self.rooms.lock().unwrap().remove(room_id);
+        }
+    }
+
+    /// Handles to all rooms, sorted by id. The rooms themselves are not locked.
+    pub fn all(&self) -> Vec<Arc<Room>> {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        rooms
+    }
+
+    /// Public information about all rooms, sorted by id
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.all().iter().map(|room| room.info()).collect()
+    }
+
+    fn insert(&self, id: RoomId, config: &Config, state: netcode::State) -> Arc<Room> {
+        let room = self.start(id.clone(), config, state);
+        self.rooms.lock().unwrap().insert(id, room.clone());
+        room
+    }
+
+    /// Opens a room and starts its tick and broadcast loops, without adding it to the rooms
+    fn start(&self, id: RoomId, config: &Config, state: netcode::State) -> Arc<Room> {
+        let recorder = config
+            .replay_dir
+            .as_deref()
+            .and_then(|dir| open_replay(dir, &id, &state));
+        let room = Arc::new(Room {
+            id: id.clone(),
+            state: Mutex::new(state),
+            members: Default::default(),
+            spectators: AtomicUsize::new(0),
+            join_queue: config.join_queue.then(Default::default),
+            interest_radius: config.interest_radius,
+            recorder: Mutex::new(recorder),
+            ai_players: config.ai_players.clone(),
+        });
+        room.spawn_ai_players();
+        start_periodic_tick(
+            Arc::downgrade(&room),
+            self.intervals.tick.subscribe(),
+            self.stopped(),
+        );
+        start_periodic_broadcast_to_room(
+            Arc::downgrade(&room),
+            self.intervals.broadcast.subscribe(),
+            self.stopped(),
+        );
+        room
+    }
+}
+
+/// Starts recording a room's game to a new replay file in the given directory.
+/// The room is not recorded if the file cannot be created.
+fn open_replay(
+    dir: &Path,
+    room_id: &str,
+    state: &netcode::State,
+) -> Option<ReplayWriter<BufWriter<File>>> {
+    let header = ReplayHeader::new(room_id.to_string(), state);
+    let path = dir.join(format!(
+        "{room_id}-{}.jsonl",
+        header.started_at.format("%Y%m%dT%H%M%S%.3f")
+    ));
+    let writer = std::fs::create_dir_all(dir)
+        .and_then(|()| File::create(&path))
+        .map_err(ReplayError::from)
+        .and_then(|file| ReplayWriter::new(BufWriter::new(file), &header));
+    match writer {
+        Ok(writer) => {
+            info!(room = %room_id, path = %path.display(), "recording the room's replay");
+            Some(writer)
+        }
+        Err(err) => {
+            error!(room = %room_id, path = %path.display(), %err, "could not start the replay");
+            None
+        }
+    }
+}
+
+// Waits for the next tick of an interval whose period can be changed while waiting.
+// Returns false once the rooms are stopped.
+async fn next_tick(
+    interval: &mut Interval,
+    period: &mut watch::Receiver<Duration>,
+    stopped: &mut watch::Receiver<bool>,
+) -> bool {
+    loop {
+        tokio::select! {
+            _ = interval.tick() => return true,
+            Ok(()) = period.changed() => {
+                *interval = tokio::time::interval(*period.borrow_and_update());
+            }
+            _ = stopped.wait_for(|stopped| *stopped) => return false,
+        }
+    }
+}
+
+// Starts ticking the room's simulation periodically, until the room is dropped or the rooms are
+// stopped.
+fn start_periodic_tick(
+    room: Weak<Room>,
+    mut period: watch::Receiver<Duration>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(*period.borrow_and_update());
+
+        loop {
+            if !next_tick(&mut interval, &mut period, &mut stopped).await {
+                return;
+            }
+            let Some(room) = room.upgrade() else {
+                return;
+            };
+            let mut state = room.lock_state();
+            let started = Instant::now();
+            state.tick();
+            METRICS.record_tick(started.elapsed());
+            room.record(ReplayEntry {
+                at: state.timestamp,
+                player_id: None,
+                event: ReplayEvent::Tick,
+            });
+        }
+    });
+}
+
+// Starts sending the room's state periodically to each socket in the room, until the room is
+// dropped or the rooms are stopped. Each socket only receives the acknowledgements of its own
+// player, and the players in its area of interest.
+fn start_periodic_broadcast_to_room(
+    room: Weak<Room>,
+    mut period: watch::Receiver<Duration>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(*period.borrow_and_update());
+        // Players in the game as of the last broadcast
+        let mut players = HashSet::new();
+
+        loop {
+            if !next_tick(&mut interval, &mut period, &mut stopped).await {
+                return;
+            }
+            let Some(room) = room.upgrade() else {
+                return;
+            };
+
+            let state = {
+                let mut state = room.lock_state();
+                let copy = state.clone();
+                state.clear_ack();
+                copy
+            };
+            let members = room
+                .members
+                .lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+            // Every member without a player receives the same state
+            let mut shared_message = None;
+            for session in members.iter().filter_map(Weak::upgrade) {
+                let mut session = session.lock().unwrap();
+                let message = match (session.player_id, room.interest_radius) {
+                    (_, Some(radius)) => {
+                        interest_view(&state, &mut session, radius, &players).message()
+                    }
+                    (Some(player_id), None) => state.view(Some(player_id), None).message(),
+                    (None, None) => shared_message
+                        .get_or_insert_with(|| state.view(None, None).message())
+                        .clone(),
+                };
+                METRICS.record_broadcast(message.len());
+                if let Err(e) = session.socket.emit(STATE_CHANNEL, &message) {
+                    warn!(room = %room.id, socket = %session.socket.id, %e, "failed to send the state");
+                }
+            }
+            players = state.players.keys().copied().collect();
+        }
+    });
+}
+
+/// The state sent to a member of a room: the players within [radius] units of its own player,
+/// with the players that entered or left its area of interest since the last broadcast.
+/// Members without a player see every player.
+fn interest_view(
+    state: &netcode::State,
+    session: &mut Session,
+    radius: f64,
+    previous_players: &HashSet<usize>,
+) -> netcode::State {
+    let mut view = state.view(session.player_id, Some(radius));
+    let visible = view.players.keys().copied().collect::<HashSet<_>>();
+
+    // Players new to the game have joined rather than entered the area of interest
+    let mut entered = visible
+        .difference(&session.visible)
+        .filter(|id| previous_players.contains(id))
+        .copied()
+        .collect::<Vec<_>>();
+    // Players that left the game are gone rather than out of the area of interest
+    let mut left = session
+        .visible
+        .difference(&visible)
+        .filter(|id| state.players.contains_key(id))
+        .copied()
+        .collect::<Vec<_>>();
+    entered.sort_unstable();
+    left.sort_unstable();
+
+    session.visible = visible;
+    view.interest = Some(InterestChanges { entered, left });
+    view
+}
+
+#[cfg(test)]
+mod tests {
+    use netcode::state::fixture::join_request;
+    use socketioxide::SocketIo;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::rate_limit::{RateLimiter, TokenBucket};
+
+    /// A socket connected without a network, with the packets the server sends to it
+    struct TestSocket {
+        socket: SocketRef,
+        session: SharedSession,
+        packets: mpsc::Receiver<engineioxide::Packet>,
+    }
+
+    impl TestSocket {
+        /// Connects a socket and puts it in the room
+        async fn connect(
+            io: &SocketIo,
+            sockets: &mut mpsc::UnboundedReceiver<SocketRef>,
+            room: &Arc<Room>,
+            config: &Config,
+        ) -> Self {
+            let (_, packets) = io.new_dummy_sock("/", ()).await;
+            let socket = sockets.recv().await.unwrap();
+            let session = Arc::new(Mutex::new(Session {
+                socket: socket.clone(),
+                room: room.clone(),
+                player_id: None,
+                spectating: false,
+                visible: HashSet::new(),
+                rtt: None,
+                rate_limiter: RateLimiter::new(config),
+                chat_limiter: TokenBucket::new(config.chat_messages_per_second, config.chat_burst),
+            }));
+            room.enter(&socket, &session);
+            Self {
+                socket,
+                session,
+                packets,
+            }
+        }
+
+        fn join(&self, name: &str) -> Result<JoinResponse, JoinRejection> {
+            let mut session = self.session.lock().unwrap();
+            let room = session.room.clone();
+            room.join(
+                &self.socket,
+                &mut session,
+                &self.session,
+                join_request(name),
+            )
+        }
+
+        /// Waits for the next message sent to the socket on the channel
+        async fn receive(&mut self, channel: &str) -> String {
+            loop {
+                let packet = tokio::time::timeout(Duration::from_secs(1), self.packets.recv())
+                    .await
+                    .expect("no message received")
+                    .unwrap();
+                let engineioxide::Packet::Message(text) = packet else {
+                    continue;
+                };
+                // Socket.IO event packets, such as `2["join","..."]`
+                let Some(event) = text.strip_prefix('2') else {
+                    continue;
+                };
+                let (name, payload): (String, String) = serde_json::from_str(event).unwrap();
+                if name == channel {
+                    return payload;
+                }
+            }
+        }
+    }
+
+    /// Opens a room with the given number of sockets in it
+    async fn open_room(config: &Config, sockets: usize) -> (Arc<Room>, Vec<TestSocket>) {
+        let (_, io) = SocketIo::new_svc();
+        let (sender, mut connected) = mpsc::unbounded_channel();
+        io.ns("/", move |socket: SocketRef| {
+            let _ = sender.send(socket);
+        });
+        let room = Rooms::new(config).create(config, None).unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..sockets {
+            clients.push(TestSocket::connect(&io, &mut connected, &room, config).await);
+        }
+        (room, clients)
+    }
+
+    #[tokio::test]
+    async fn test_create_caps_rooms_and_players() {
+        let config = Config {
+            max_players: Some(4),
+            max_rooms: 3,
+            ..Default::default()
+        };
+        let rooms = Rooms::new(&config);
+        rooms.open(&config, BTreeMap::new());
+
+        let max_players = |room: Arc<Room>| room.lock_state().config.max_players;
+        assert_eq!(
+            max_players(rooms.create(&config, Some(0)).unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            max_players(rooms.create(&config, Some(10)).unwrap()),
+            Some(4)
+        );
+        assert!(matches!(
+            rooms.create(&config, None),
+            Err(RoomError::TooManyRooms(3))
+        ));
+        // Closing a room makes room for another one
+        rooms.remove("room-1");
+        assert_eq!(max_players(rooms.create(&config, None).unwrap()), Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_exit_admits_queued_socket() {
+        let config = Config {
+            max_players: Some(1),
+            join_queue: true,
+            ..Default::default()
+        };
+        let (room, mut clients) = open_room(&config, 3).await;
+
+        clients[0].join("Alice").unwrap();
+        assert_eq!(clients[1].join("Bob").unwrap_err().queue_position, Some(1));
+        assert_eq!(
+            clients[2].join("Carol").unwrap_err().queue_position,
+            Some(2)
+        );
+
+        let exit = {
+            let mut session = clients[0].session.lock().unwrap();
+            room.exit(&clients[0].socket, &mut session)
+        };
+        assert!(exit.freed && !exit.empty);
+        room.admit_queued();
+
+        let response: JoinResponse =
+            serde_json::from_str(&clients[1].receive(JOIN_CHANNEL).await).unwrap();
+        assert_eq!(response.name, "Bob");
+        assert_eq!(
+            clients[1].session.lock().unwrap().player_id,
+            Some(response.player_id)
+        );
+        // Carol moved up the queue
+        let rejection: JoinRejection =
+            serde_json::from_str(&clients[2].receive(JOIN_REJECTED_CHANNEL).await).unwrap();
+        assert_eq!(rejection.queue_position, Some(1));
+        assert_eq!(clients[2].session.lock().unwrap().player_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_reset_clears_sessions() {
+        let (room, mut clients) = open_room(&Config::default(), 1).await;
+        clients[0].join("Alice").unwrap();
+
+        room.reset();
+        assert_eq!(clients[0].session.lock().unwrap().player_id, None);
+        assert_eq!(room.info().players, 0);
+        let response: RoomResponse =
+            serde_json::from_str(&clients[0].receive(ROOM_CHANNEL).await).unwrap();
+        assert!(matches!(response, RoomResponse::Joined { .. }));
+        // The client can join the new game
+        clients[0].join("Alice").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spectating_leaves_queue() {
+        let config = Config {
+            max_players: Some(1),
+            join_queue: true,
+            ..Default::default()
+        };
+        let (room, mut clients) = open_room(&config, 3).await;
+        clients[0].join("Alice").unwrap();
+        clients[1].join("Bob").unwrap_err();
+        clients[2].join("Carol").unwrap_err();
+
+        let left_queue = {
+            let mut session = clients[1].session.lock().unwrap();
+            room.spectate(&clients[1].socket, &mut session)
+        };
+        assert!(left_queue);
+        assert_eq!(room.info().spectators, 1);
+        room.admit_queued();
+        // Carol moved up the queue, still waiting for Alice's slot
+        let rejection: JoinRejection =
+            serde_json::from_str(&clients[2].receive(JOIN_REJECTED_CHANNEL).await).unwrap();
+        assert_eq!(rejection.queue_position, Some(1));
+
+        room.reset();
+        assert_eq!(room.info().spectators, 0);
+        assert!(!clients[1].session.lock().unwrap().spectating);
+    }
+}
@@ -0,0 +1,88 @@
+//! State kept for every connected socket.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use socketioxide::{extract::SocketRef, socket::Sid};
+use tracing::{info_span, Span};
+
+use crate::{
+    rate_limit::{RateLimiter, TokenBucket},
+    room::Room,
+};
+
+/// The room a connected socket is in, and the player it controls in that room
+#[derive(Debug)]
+pub struct Session {
+    pub socket: SocketRef,
+    pub room: Arc<Room>,
+    pub player_id: Option<usize>,
+    /// Whether the socket watches the room's game without a player
+    pub spectating: bool,
+    /// Players in the socket's area of interest as of the last state broadcast
+    pub visible: HashSet<usize>,
+    /// Round trip time measured by the last answered ping
+    pub rtt: Option<Duration>,
+    /// Allowance of actions the socket can still send
+    pub rate_limiter: RateLimiter,
+    /// Allowance of chat messages the socket can still send
+    pub chat_limiter: TokenBucket,
+}
+
+/// Represents all possible errors that can occur when a socket acts on its room's game
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    #[error("[ERROR - NOT JOINED] This connection has not joined the game.")]
+    NotJoined,
+    #[error("[ERROR - ALREADY JOINED] This connection already controls player {0}.")]
+    AlreadyJoined(usize),
+}
+
+impl Session {
+    /// Id of the player owned by the socket. Sockets own at most one player at a time
+    pub fn player(&self) -> Result<usize, SessionError> {
+        self.player_id.ok_or(SessionError::NotJoined)
+    }
+
+    /// Span carrying the socket, room and player of the session, for the logs of its handlers
+    pub fn span(&self) -> Span {
+        info_span!(
+            "session",
+            socket = %self.socket.id,
+            room = %self.room.id,
+            player_id = ?self.player_id
+        )
+    }
+}
+
+/// Handle to a session that is shared between the socket's handlers
+pub type SharedSession = Arc<Mutex<Session>>;
+
+/// Sessions of all connected sockets
+#[derive(Debug, Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<Sid, SharedSession>>,
+}
+
+impl Sessions {
+    pub fn insert(&self, sid: Sid, session: SharedSession) {
+        self.sessions.lock().unwrap().insert(sid, session);
+    }
+
+    pub fn remove(&self, sid: &Sid) {
+        self.sessions.lock().unwrap().remove(sid);
+    }
+
+    /// Number of connected sockets
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Handles to all sessions. The sessions themselves are not locked.
+    pub fn all(&self) -> Vec<SharedSession> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+}
@@ -0,0 +1,78 @@
+//! Graceful shutdown on SIGINT or SIGTERM, telling the clients why they are disconnected.
+
+use std::path::{Path, PathBuf};
+
+use netcode::{event::DisconnectNotice, DISCONNECT_NOTICE_CHANNEL};
+use socketioxide::SocketIo;
+use tracing::{error, info, warn};
+
+use crate::{persistence, AppState};
+
+/// Represents all possible errors that can occur when writing the final snapshot
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("[ERROR - SNAPSHOT] Could not serialize the rooms' state: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("[ERROR - SNAPSHOT] Could not write snapshot file {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+/// Waits for SIGINT or SIGTERM, returning the name of the received signal
+pub async fn signal() -> &'static str {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for SIGINT");
+        "SIGINT"
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+        "SIGTERM"
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<&'static str>();
+
+    tokio::select! {
+        signal = interrupt => signal,
+        signal = terminate => signal,
+    }
+}
+
+/// Stops every room, saves the world and writes the final snapshot if configured, then
+/// disconnects every client with a shutdown notice carrying the reason.
+pub async fn shutdown(app: &AppState, io: &SocketIo, reason: String) {
+    app.rooms.stop();
+
+    if let Some(path) = &app.config.world_save {
+        match persistence::save(app, path) {
+            Ok(()) => info!(path = %path.display(), "world saved"),
+            Err(err) => error!(%err, "could not save the world"),
+        }
+    }
+
+    if let Some(path) = &app.config.final_snapshot {
+        match write_snapshot(app, path) {
+            Ok(()) => info!(path = %path.display(), "final snapshot written"),
+            Err(err) => error!(%err, "could not write the final snapshot"),
+        }
+    }
+
+    let notice = serde_json::to_string(&DisconnectNotice::Shutdown { reason }).unwrap();
+    if let Err(e) = io.emit(DISCONNECT_NOTICE_CHANNEL, &notice).await {
+        warn!(%e, "failed to send the shutdown notice");
+    }
+    if let Err(e) = io.disconnect().await {
+        warn!(%e, "failed to disconnect the clients");
+    }
+}
+
+/// Writes the game state of every room to a JSON file, by room id
+fn write_snapshot(app: &AppState, path: &Path) -> Result<(), SnapshotError> {
+    let snapshot = serde_json::to_string_pretty(&app.rooms.snapshot())?;
+    std::fs::write(path, snapshot).map_err(|err| SnapshotError::Write(path.to_path_buf(), err))
+}